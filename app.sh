@@ -5,6 +5,8 @@ set -e
 # Path to built binary (adjust if needed)
 KBD_BIN=target/release/kbd
 INSTALL_BIN=/usr/local/bin/kbd
+KBDCTL_BIN=target/release/kbdctl
+INSTALL_KBDCTL=/usr/local/bin/kbdctl
 SERVICE_FILE=kbd.service
 INSTALL_SERVICE=/etc/systemd/system/kbd.service
 CONFIG_SRC=config.toml
@@ -28,6 +30,8 @@ install_kbd_service() {
 	# Copy binary
 	sudo cp "$KBD_BIN" "$INSTALL_BIN"
 	sudo chmod 755 "$INSTALL_BIN"
+	sudo cp "$KBDCTL_BIN" "$INSTALL_KBDCTL"
+	sudo chmod 755 "$INSTALL_KBDCTL"
 
 	# Copy service file
 	sudo cp "$SERVICE_FILE" "$INSTALL_SERVICE"
@@ -65,6 +69,7 @@ uninstall_kbd_service() {
 	# Remove service file and binary
 	sudo rm -f "$INSTALL_SERVICE"
 	sudo rm -f "$INSTALL_BIN"
+	sudo rm -f "$INSTALL_KBDCTL"
 
 	# Reload systemd
 	sudo systemctl daemon-reload
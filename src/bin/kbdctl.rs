@@ -0,0 +1,254 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+#[cfg(debug_assertions)]
+const SOCKET_PATH: &str = "/tmp/kbd.sock";
+#[cfg(not(debug_assertions))]
+const SOCKET_PATH: &str = "/run/kbd.sock";
+
+const USAGE: &str = "usage: kbdctl [--json] [--socket <path>] <command>
+commands:
+  status                      state, uptime, bindings and active layers
+  reload                      read the config again
+  pause [duration]            pass keys through, for good or e.g. 30s, 5m
+  resume
+  layer <name> on|off|toggle
+  inject <keys>               e.g. \"leftmeta down, f23, wait 50, leftmeta up\",
+                              runs through the bindings like typed keys
+  layers                      layers and whether they are on
+  bindings
+  devices                     grabbed input devices
+  log [filter]                show or set what is logged, e.g. \"warn,key_buffer=trace\"
+  metrics                     input to output latency, Prometheus text format";
+
+/// Sends one command, returns the data lines of a successful reply
+fn request(socket: &str, command: &str) -> Result<Result<Vec<String>, String>, Box<dyn Error>> {
+    let stream =
+        UnixStream::connect(socket).map_err(|e| format!("Can't reach kbd on {socket}: {e}"))?;
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{command}")?;
+    let mut data = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "ok" {
+            return Ok(Ok(data));
+        }
+        if let Some(error) = line.strip_prefix("error ") {
+            return Ok(Err(error.to_string()));
+        }
+        data.push(line);
+    }
+    Err("kbd closed the connection".into())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Numbers stay numbers, everything else becomes a string
+fn json_value(s: &str) -> String {
+    match s.parse::<i64>() {
+        Ok(n) => n.to_string(),
+        Err(_) => json_string(s),
+    }
+}
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(k, v)| format!("{}:{}", json_string(k), v))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Reply of `command` as JSON, the shape depends on the command
+fn to_json(command: &str, lines: &[String]) -> String {
+    let array = |items: Vec<String>| format!("[{}]", items.join(","));
+    match command {
+        "list-bindings" => array(
+            lines
+                .iter()
+                .map(|line| {
+                    let (layer, rest) =
+                        match line.strip_prefix('[').and_then(|l| l.split_once("] ")) {
+                            Some((layer, rest)) => (json_string(layer), rest),
+                            None => ("null".to_string(), line.as_str()),
+                        };
+                    let (trigger, action) = rest.split_once(" = ").unwrap_or((rest, ""));
+                    json_object(&[
+                        ("layer", layer),
+                        ("trigger", json_string(trigger)),
+                        ("action", json_string(action)),
+                    ])
+                })
+                .collect(),
+        ),
+        "list-devices" => array(
+            lines
+                .iter()
+                .map(|line| {
+                    let (path, name) = line.split_once(' ').unwrap_or((line, ""));
+                    json_object(&[("path", json_string(path)), ("name", json_string(name))])
+                })
+                .collect(),
+        ),
+        "list-layers" => array(
+            lines
+                .iter()
+                .map(|line| {
+                    let (name, state) = line.rsplit_once(' ').unwrap_or((line, ""));
+                    json_object(&[
+                        ("name", json_string(name)),
+                        ("on", (state == "on").to_string()),
+                    ])
+                })
+                .collect(),
+        ),
+        _ => {
+            // "key value" lines, status lists its layers space separated
+            let fields: Vec<(&str, String)> = lines
+                .iter()
+                .map(|line| {
+                    let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                    let value = if key == "layers" {
+                        array(value.split_whitespace().map(json_string).collect())
+                    } else {
+                        json_value(value)
+                    };
+                    (key, value)
+                })
+                .collect();
+            json_object(&fields)
+        }
+    }
+}
+
+/// Daemon command for the command line arguments
+fn to_command(args: &[&str]) -> Option<String> {
+    let command = match args {
+        ["status"] | ["reload"] | ["pause"] | ["resume"] => args[0].to_string(),
+        ["pause", duration] => format!("pause {duration}"),
+        ["layer", name, state @ ("on" | "off" | "toggle")] => format!("layer {name} {state}"),
+        ["layers"] => "list-layers".to_string(),
+        ["bindings"] => "list-bindings".to_string(),
+        ["devices"] => "list-devices".to_string(),
+        ["log"] => "log".to_string(),
+        ["metrics"] => "metrics".to_string(),
+        ["log", filter] => format!("log {filter}"),
+        ["inject", keys @ ..] if !keys.is_empty() => format!("inject {}", keys.join(" ")),
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let mut json = false;
+    let mut socket = std::env::var("KBD_SOCKET").unwrap_or(SOCKET_PATH.to_string());
+    loop {
+        match args.as_slice() {
+            ["--json", ..] => {
+                json = true;
+                args.remove(0);
+            }
+            ["--socket", path, ..] => {
+                socket = path.to_string();
+                args.drain(..2);
+            }
+            _ => break,
+        }
+    }
+    let Some(command) = to_command(&args) else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+    let name = command.split(' ').next().unwrap_or_default();
+    match request(&socket, &command)? {
+        // Metrics are in a format of their own
+        Ok(lines) if json && name != "metrics" => println!("{}", to_json(name, &lines)),
+        Ok(lines) => lines.iter().for_each(|line| println!("{line}")),
+        Err(error) => {
+            if json {
+                println!("{}", json_object(&[("error", json_string(&error))]));
+            } else {
+                eprintln!("kbdctl: {error}");
+            }
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s: &[&str]) -> Vec<String> {
+        s.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_to_command() {
+        assert_eq!(to_command(&["pause", "30s"]).unwrap(), "pause 30s");
+        assert_eq!(to_command(&["layer", "nav", "on"]).unwrap(), "layer nav on");
+        assert_eq!(to_command(&["bindings"]).unwrap(), "list-bindings");
+        assert_eq!(
+            to_command(&["inject", "a down,", "a up"]).unwrap(),
+            "inject a down, a up"
+        );
+        assert!(to_command(&["inject"]).is_none());
+        assert_eq!(to_command(&["log"]).unwrap(), "log");
+        assert_eq!(to_command(&["metrics"]).unwrap(), "metrics");
+        assert_eq!(
+            to_command(&["log", "warn,exec=debug"]).unwrap(),
+            "log warn,exec=debug"
+        );
+        assert!(to_command(&["layer", "nav", "maybe"]).is_none());
+        assert!(to_command(&[]).is_none());
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            to_json(
+                "status",
+                &lines(&["state running", "uptime_s 12", "layers nav sym"])
+            ),
+            r#"{"state":"running","uptime_s":12,"layers":["nav","sym"]}"#
+        );
+        assert_eq!(
+            to_json(
+                "list-bindings",
+                &lines(&["a = b", "[nav] h = left", r#"f21 = exec "say \"hi\"""#])
+            ),
+            concat!(
+                r#"[{"layer":null,"trigger":"a","action":"b"},"#,
+                r#"{"layer":"nav","trigger":"h","action":"left"},"#,
+                r#"{"layer":null,"trigger":"f21","action":"exec \"say \\\"hi\\\"\""}]"#
+            )
+        );
+        assert_eq!(
+            to_json("list-layers", &lines(&["nav on", "sym off"])),
+            r#"[{"name":"nav","on":true},{"name":"sym","on":false}]"#
+        );
+        assert_eq!(
+            to_json("list-devices", &lines(&["/dev/input/event3 AT keyboard"])),
+            r#"[{"path":"/dev/input/event3","name":"AT keyboard"}]"#
+        );
+        assert_eq!(to_json("pause", &[]), "{}");
+    }
+}
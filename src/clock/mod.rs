@@ -0,0 +1,152 @@
+#![allow(dead_code)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+pub use virtual_clock::VirtualClock;
+
+mod virtual_clock;
+
+pub type Callback = Box<dyn FnMut() + Send>;
+pub type SafeClock = Arc<dyn Clock>;
+
+/// Keeps a scheduled callback alive, dropping it cancels the callback
+pub struct ClockGuard {
+    _guard: Box<dyn Send>,
+}
+
+impl ClockGuard {
+    pub fn new<G: Send + 'static>(guard: G) -> Self {
+        ClockGuard {
+            _guard: Box::new(guard),
+        }
+    }
+}
+
+/// Sets the flag when dropped, callbacks check it right before running
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Source of time for everything that delays events
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the clock was created
+    fn now_ms(&self) -> i64;
+    fn schedule_with_delay(&self, delay: chrono::Duration, cb: Callback) -> ClockGuard;
+    /// Runs `cb` every `interval`, first time one interval from now
+    fn schedule_repeating(&self, interval: chrono::Duration, cb: Callback) -> ClockGuard;
+}
+
+type Job = (Arc<Mutex<Callback>>, Arc<AtomicBool>);
+
+/// Wall clock. The `timer` thread only hands due callbacks over to a worker
+/// thread: `timer` keeps its queue locked while running a callback, so a
+/// callback scheduling more work would deadlock there.
+pub struct SystemClock {
+    timer: timer::Timer,
+    start: Instant,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            for (cb, cancelled) in rx {
+                if !cancelled.load(Ordering::Relaxed) {
+                    (cb.lock().unwrap())();
+                }
+            }
+        });
+        SystemClock {
+            timer: timer::Timer::new(),
+            start: Instant::now(),
+            jobs,
+        }
+    }
+
+    fn _job(&self, cb: Callback) -> (Callback, Cancel) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let job: Job = (Arc::new(Mutex::new(cb)), cancelled.clone());
+        let jobs = self.jobs.clone();
+        let dispatch = Box::new(move || {
+            // Worker is gone only when the clock is being dropped
+            let _ = jobs.send(job.clone());
+        });
+        (dispatch, Cancel(cancelled))
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+
+    fn schedule_with_delay(&self, delay: chrono::Duration, cb: Callback) -> ClockGuard {
+        let (dispatch, cancel) = self._job(cb);
+        ClockGuard::new((self.timer.schedule_with_delay(delay, dispatch), cancel))
+    }
+
+    fn schedule_repeating(&self, interval: chrono::Duration, cb: Callback) -> ClockGuard {
+        let (dispatch, cancel) = self._job(cb);
+        ClockGuard::new((self.timer.schedule_repeating(interval, dispatch), cancel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_system_clock() {
+        let clock = SystemClock::new();
+        let (tx, rx) = channel::<i64>();
+        let tx = Mutex::new(tx);
+        let _g = clock.schedule_with_delay(
+            chrono::Duration::milliseconds(5),
+            Box::new(move || tx.lock().unwrap().send(5).unwrap()),
+        );
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1)), Ok(5));
+        assert!(clock.now_ms() >= 5);
+    }
+
+    #[test]
+    fn test_system_clock_nested_and_repeating() {
+        let clock = Arc::new(SystemClock::new());
+        let (tx, rx) = channel::<i64>();
+        let tx = Mutex::new(tx);
+        let c = clock.clone();
+        let nested = Arc::new(Mutex::new(Vec::new()));
+        let n = nested.clone();
+        // Scheduling from inside a callback must not block the timer
+        let _g = clock.schedule_repeating(
+            chrono::Duration::milliseconds(5),
+            Box::new(move || {
+                let tx = Mutex::new(tx.lock().unwrap().clone());
+                n.lock().unwrap().push(c.schedule_with_delay(
+                    chrono::Duration::milliseconds(1),
+                    Box::new(move || tx.lock().unwrap().send(1).unwrap()),
+                ));
+            }),
+        );
+        for _ in 0..3 {
+            assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1)), Ok(1));
+        }
+        drop(_g);
+        nested.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{Callback, Cancel, Clock, ClockGuard};
+
+struct Task {
+    cb: Callback,
+    cancelled: Arc<AtomicBool>,
+    interval: Option<i64>,
+}
+
+#[derive(Default)]
+struct State {
+    now: i64,
+    seq: u64,
+    // Ordered by due time, then by scheduling order
+    tasks: BTreeMap<(i64, u64), Task>,
+}
+
+/// Clock that only moves when told to. Callbacks run on the thread calling
+/// `advance`, in due order, so tests get exact and repeatable timelines.
+#[derive(Default)]
+pub struct VirtualClock {
+    state: Mutex<State>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(VirtualClock::default())
+    }
+
+    pub fn advance(&self, ms: i64) {
+        let target = self.now_ms() + ms.max(0);
+        self.advance_to(target);
+    }
+
+    /// Runs every callback due at or before `target` and moves the clock there
+    pub fn advance_to(&self, target: i64) {
+        loop {
+            let task = {
+                let mut state = self.state.lock().unwrap();
+                let due = match state.tasks.first_key_value() {
+                    Some((key, _)) if key.0 <= target => *key,
+                    _ => break,
+                };
+                state.now = state.now.max(due.0);
+                state.tasks.remove(&due).map(|t| (due.0, t))
+            };
+            // Lock is released, callbacks may schedule more work
+            if let Some((due, mut task)) = task
+                && !task.cancelled.load(Ordering::Relaxed)
+            {
+                (task.cb)();
+                if let Some(interval) = task.interval {
+                    let mut state = self.state.lock().unwrap();
+                    let seq = state.seq;
+                    state.seq += 1;
+                    state.tasks.insert((due + interval, seq), task);
+                }
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.now = state.now.max(target);
+    }
+
+    /// Due time of the earliest callback that hasn't been cancelled
+    pub fn next_due(&self) -> Option<i64> {
+        let state = self.state.lock().unwrap();
+        state
+            .tasks
+            .iter()
+            .find(|(_, t)| !t.cancelled.load(Ordering::Relaxed))
+            .map(|(key, _)| key.0)
+    }
+
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .tasks
+            .values()
+            .filter(|t| !t.cancelled.load(Ordering::Relaxed))
+            .count()
+    }
+
+    fn _schedule(&self, delay: i64, interval: Option<i64>, cb: Callback) -> ClockGuard {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut state = self.state.lock().unwrap();
+        let due = state.now + delay;
+        let seq = state.seq;
+        state.seq += 1;
+        state.tasks.insert(
+            (due, seq),
+            Task {
+                cb,
+                cancelled: cancelled.clone(),
+                interval,
+            },
+        );
+        ClockGuard::new(Cancel(cancelled))
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now_ms(&self) -> i64 {
+        self.state.lock().unwrap().now
+    }
+
+    fn schedule_with_delay(&self, delay: chrono::Duration, cb: Callback) -> ClockGuard {
+        self._schedule(delay.num_milliseconds().max(0), None, cb)
+    }
+
+    fn schedule_repeating(&self, interval: chrono::Duration, cb: Callback) -> ClockGuard {
+        // Zero interval would spin forever inside `advance`
+        let interval = interval.num_milliseconds().max(1);
+        self._schedule(interval, Some(interval), cb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(log: &Arc<Mutex<Vec<(i64, u32)>>>, clock: &Arc<VirtualClock>, id: u32) -> Callback {
+        let log = log.clone();
+        let clock = clock.clone();
+        Box::new(move || log.lock().unwrap().push((clock.now_ms(), id)))
+    }
+
+    #[test]
+    fn test_virtual_clock_order() {
+        let clock = VirtualClock::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let _g1 =
+            clock.schedule_with_delay(chrono::Duration::milliseconds(30), record(&log, &clock, 1));
+        let _g2 =
+            clock.schedule_with_delay(chrono::Duration::milliseconds(10), record(&log, &clock, 2));
+        let _g3 =
+            clock.schedule_with_delay(chrono::Duration::milliseconds(10), record(&log, &clock, 3));
+        let _g4 =
+            clock.schedule_with_delay(chrono::Duration::milliseconds(-5), record(&log, &clock, 4));
+
+        clock.advance(0);
+        assert_eq!(*log.lock().unwrap(), vec![(0, 4)]);
+        clock.advance(20);
+        assert_eq!(*log.lock().unwrap(), vec![(0, 4), (10, 2), (10, 3)]);
+        assert_eq!(clock.now_ms(), 20);
+        assert_eq!(clock.next_due(), Some(30));
+        clock.advance(10);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![(0, 4), (10, 2), (10, 3), (30, 1)]
+        );
+        assert_eq!(clock.pending(), 0);
+    }
+
+    #[test]
+    fn test_virtual_clock_cancel() {
+        let clock = VirtualClock::new();
+        let cntr = Arc::new(Mutex::new(0));
+        let mut guards = Vec::new();
+        for _ in 0..10_000 {
+            let counter = Arc::clone(&cntr);
+            guards.push(clock.schedule_with_delay(
+                chrono::Duration::milliseconds(1),
+                Box::new(move || *counter.lock().unwrap() += 1),
+            ));
+        }
+        assert_eq!(clock.pending(), 10_000);
+        guards.truncate(100);
+        clock.advance(1);
+        assert_eq!(*cntr.lock().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_virtual_clock_nested_schedule() {
+        let clock = VirtualClock::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let guards = Arc::new(Mutex::new(Vec::new()));
+        let (c, l, g) = (clock.clone(), log.clone(), guards.clone());
+        let _g = clock.schedule_with_delay(
+            chrono::Duration::milliseconds(5),
+            Box::new(move || {
+                let guard =
+                    c.schedule_with_delay(chrono::Duration::milliseconds(5), record(&l, &c, 2));
+                g.lock().unwrap().push(guard);
+            }),
+        );
+        clock.advance(100);
+        assert_eq!(*log.lock().unwrap(), vec![(10, 2)]);
+        assert_eq!(clock.now_ms(), 100);
+    }
+
+    #[test]
+    fn test_virtual_clock_repeating() {
+        let clock = VirtualClock::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let g =
+            clock.schedule_repeating(chrono::Duration::milliseconds(10), record(&log, &clock, 1));
+        clock.advance(35);
+        assert_eq!(*log.lock().unwrap(), vec![(10, 1), (20, 1), (30, 1)]);
+        assert_eq!(clock.next_due(), Some(40));
+        drop(g);
+        clock.advance(100);
+        assert_eq!(log.lock().unwrap().len(), 3);
+        assert_eq!(clock.pending(), 0);
+    }
+}
@@ -1,15 +1,72 @@
 use std::hash::{DefaultHasher, Hash, Hasher};
 
 use super::KeyCombinationHashed;
+use super::layout::Layout;
 use super::parser::{Expr, Expressions};
-use crate::key_buffer::{Action, Event, KeyDeque};
+use super::unicode::{UnicodeMethod, compose_sequence};
+use crate::exec::ExecCommand;
+use crate::key_buffer::{Action, Event, Key, KeyDeque, UKey};
+use crate::log_error;
+use crate::log_warn;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+const DEFAULT_TYPE_DELAY_MS: u64 = 5;
 
 fn process_config() {}
 
+/// `[exec]` section, who runs the commands of `exec` actions
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ExecConfig {
+    pub user: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Something an action does at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Key(Event),
+    Exec(ExecCommand),
+}
+
+/// Settings that shape the events of an action
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionOptions {
+    pub layout: Layout,
+    /// Pause after each character of `type`, also between the steps of
+    /// `unicode`
+    pub type_delay_ms: u64,
+    pub unicode_method: UnicodeMethod,
+    pub compose_key: Key,
+    pub exec: ExecConfig,
+}
+
+impl Default for ActionOptions {
+    fn default() -> Self {
+        ActionOptions {
+            layout: Layout::default(),
+            type_delay_ms: DEFAULT_TYPE_DELAY_MS,
+            unicode_method: UnicodeMethod::default(),
+            compose_key: Key::Keyboard(UKey::RightAlt),
+            exec: ExecConfig::default(),
+        }
+    }
+}
+
 pub fn get_action<'a>(
     deq: &KeyDeque,
-    combinations: &'a Vec<KeyCombinationHashed>,
+    combinations: &'a [KeyCombinationHashed],
 ) -> Option<&'a Expressions> {
+    get_binding(deq, combinations, |_| true).map(|idx| &combinations[idx].combinations.action)
+}
+
+/// Index of the first enabled binding whose keys are all in the buffer
+pub fn get_binding(
+    deq: &KeyDeque,
+    combinations: &[KeyCombinationHashed],
+    enabled: impl Fn(usize) -> bool,
+) -> Option<usize> {
     let mut key_hashes = Vec::<u64>::with_capacity(deq.len());
     for event in deq.iter() {
         let mut hasher = DefaultHasher::new();
@@ -29,48 +86,149 @@ pub fn get_action<'a>(
         };
     }
 
-    for c in combinations {
-        if all_hashes_in_combo!(c, key_hashes) {
-            return Some(&c.combinations.action);
+    combinations
+        .iter()
+        .enumerate()
+        .position(|(idx, c)| enabled(idx) && all_hashes_in_combo!(c, key_hashes))
+}
+
+/// Presses the keys in order and releases them in reverse, all at `at`
+fn tap(result: &mut Vec<(i64, Output)>, at: i64, keys: &[Key]) {
+    for key in keys {
+        result.push((
+            at,
+            Output::Key(Event {
+                action: Action::Press,
+                key: *key,
+            }),
+        ));
+    }
+    for key in keys.iter().rev() {
+        result.push((
+            at,
+            Output::Key(Event {
+                action: Action::Release,
+                key: *key,
+            }),
+        ));
+    }
+}
+
+/// Taps the key typing `c` in the layout, false if there is none
+fn tap_char(result: &mut Vec<(i64, Output)>, at: i64, c: char, options: &ActionOptions) -> bool {
+    match options.layout.key_for(c) {
+        Some((key, true)) => tap(result, at, &[UKey::LeftShift.into(), key.into()]),
+        Some((key, false)) => tap(result, at, &[key.into()]),
+        None => return false,
+    }
+    true
+}
+
+/// Appends the sequence producing `c` with the configured method, returns
+/// the time after it
+fn unicode_to_events(
+    result: &mut Vec<(i64, Output)>,
+    mut at: i64,
+    c: char,
+    options: &ActionOptions,
+) -> i64 {
+    let step = options.type_delay_ms as i64;
+    match options.unicode_method {
+        UnicodeMethod::Ibus => {
+            tap(
+                result,
+                at,
+                &[
+                    UKey::LeftControl.into(),
+                    UKey::LeftShift.into(),
+                    UKey::U.into(),
+                ],
+            );
+            at += step;
+            for digit in format!("{:x}", c as u32).chars() {
+                tap_char(result, at, digit, options);
+                at += step;
+            }
+            tap(result, at, &[UKey::Space.into()]);
+            at += step;
+        }
+        UnicodeMethod::Compose => {
+            let Some(sequence) = compose_sequence(c) else {
+                log_warn!("No compose sequence for {c:?}");
+                return at;
+            };
+            tap(result, at, &[options.compose_key]);
+            at += step;
+            for s in sequence.chars() {
+                tap_char(result, at, s, options);
+                at += step;
+            }
         }
     }
-    return None;
+    at
 }
 
-pub fn action_to_events(action: &Expressions) -> Vec<(i64, Event)> {
+/// Key events of an action, anything else it does is left out
+pub fn action_to_events(action: &Expressions, options: &ActionOptions) -> Vec<(i64, Event)> {
+    action_to_outputs(action, options)
+        .into_iter()
+        .filter_map(|(delay, output)| match output {
+            Output::Key(event) => Some((delay, event)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn action_to_outputs(action: &Expressions, options: &ActionOptions) -> Vec<(i64, Output)> {
     let mut current_delay: i64 = 0;
-    let mut result = Vec::<(i64, Event)>::with_capacity(action.len());
+    let mut result = Vec::<(i64, Output)>::with_capacity(action.len());
     for expr in action {
         match expr {
             Expr::Key(key_expr) => {
                 if let Some(action) = key_expr.action {
                     result.push((
                         current_delay,
-                        Event {
-                            action: action,
+                        Output::Key(Event {
+                            action,
                             key: key_expr.key,
-                        },
+                        }),
                     ));
                 } else {
-                    result.push((
-                        current_delay,
-                        Event {
-                            action: Action::Press,
-                            key: key_expr.key,
-                        },
-                    ));
-                    result.push((
-                        current_delay,
-                        Event {
-                            action: Action::Release,
-                            key: key_expr.key,
-                        },
-                    ));
+                    tap(&mut result, current_delay, &[key_expr.key]);
                 }
             }
             Expr::Wait(expr) => {
                 current_delay += expr.milliseconds as i64;
             }
+            Expr::Type(expr) => {
+                for c in expr.text.chars() {
+                    if !tap_char(&mut result, current_delay, c, options) {
+                        log_warn!("No key for {c:?} in {:?} layout", options.layout);
+                        continue;
+                    }
+                    current_delay += options.type_delay_ms as i64;
+                }
+            }
+            Expr::Unicode(expr) => {
+                for c in expr.text.chars() {
+                    current_delay = unicode_to_events(&mut result, current_delay, c, options);
+                }
+            }
+            Expr::Exec(expr) => {
+                // Config load rejects these, root is never the fallback
+                let Some(user) = expr.user.clone().or_else(|| options.exec.user.clone()) else {
+                    log_error!("No user to run {:?} as, skipped", expr.command);
+                    continue;
+                };
+                result.push((
+                    current_delay,
+                    Output::Exec(ExecCommand {
+                        command: expr.command.clone(),
+                        user,
+                        env: options.exec.env.clone().into_iter().collect(),
+                    }),
+                ));
+            }
         }
     }
     result
@@ -82,6 +240,7 @@ mod tests {
     use crate::key_buffer::{Action, BufferEvent, Event, KeyDeque, UKey};
 
     use super::super::config_from_str;
+    use super::super::parser::parse_expr;
     use super::*;
 
     macro_rules! events_deque {
@@ -90,10 +249,11 @@ mod tests {
             $(
                 deq.push_back(BufferEvent {
                 event: Event {
-                    key: $key,
+                    key: $key.into(),
                     action: $action,
                 },
                 guard: None,
+                read_at: std::time::Instant::now(),
                 });
             )*
             deq
@@ -146,37 +306,37 @@ mod tests {
     #[test]
     fn test_action() {
         let combo = vec![Expr::Key(KeyExpr {
-            key: UKey::A,
+            key: Key::Keyboard(UKey::A),
             action: Some(Action::Press),
         })];
         assert_eq!(
-            action_to_events(&combo),
+            action_to_events(&combo, &ActionOptions::default()),
             vec![(
                 0,
                 Event {
-                    key: UKey::A,
+                    key: Key::Keyboard(UKey::A),
                     action: Action::Press
                 }
             )]
         );
         let combo = vec![Expr::Key(KeyExpr {
-            key: UKey::A,
+            key: Key::Keyboard(UKey::A),
             action: None,
         })];
         assert_eq!(
-            action_to_events(&combo),
+            action_to_events(&combo, &ActionOptions::default()),
             vec![
                 (
                     0,
                     Event {
-                        key: UKey::A,
+                        key: Key::Keyboard(UKey::A),
                         action: Action::Press
                     }
                 ),
                 (
                     0,
                     Event {
-                        key: UKey::A,
+                        key: Key::Keyboard(UKey::A),
                         action: Action::Release
                     }
                 ),
@@ -184,33 +344,164 @@ mod tests {
         );
 
         let combo = vec![Expr::Key(KeyExpr {
-            key: UKey::LeftControl,
+            key: Key::Keyboard(UKey::LeftControl),
             action: Some(Action::Press),
         }),
         Expr::Wait(crate::config::parser::WaitExpr { milliseconds: 500 }),
         Expr::Key(KeyExpr {
-            key: UKey::LeftControl,
+            key: Key::Keyboard(UKey::LeftControl),
             action: Some(Action::Release),
         }),
         ];
         assert_eq!(
-            action_to_events(&combo),
+            action_to_events(&combo, &ActionOptions::default()),
             vec![
                 (
                     0,
                     Event {
-                        key: UKey::LeftControl,
+                        key: Key::Keyboard(UKey::LeftControl),
                         action: Action::Press
                     }
                 ),
                 (
                     500,
                     Event {
-                        key: UKey::LeftControl,
+                        key: Key::Keyboard(UKey::LeftControl),
                         action: Action::Release
                     }
                 ),
             ]
         );
     }
+
+    #[test]
+    fn test_action_type() {
+        let options = ActionOptions {
+            type_delay_ms: 10,
+            ..Default::default()
+        };
+        let event = |key: UKey, action| Event {
+            key: key.into(),
+            action,
+        };
+        assert_eq!(
+            action_to_events(&parse_expr(r#"type "Hi!""#).unwrap(), &options),
+            vec![
+                (0, event(UKey::LeftShift, Action::Press)),
+                (0, event(UKey::H, Action::Press)),
+                (0, event(UKey::H, Action::Release)),
+                (0, event(UKey::LeftShift, Action::Release)),
+                (10, event(UKey::I, Action::Press)),
+                (10, event(UKey::I, Action::Release)),
+                (20, event(UKey::LeftShift, Action::Press)),
+                (20, event(UKey::_1, Action::Press)),
+                (20, event(UKey::_1, Action::Release)),
+                (20, event(UKey::LeftShift, Action::Release)),
+            ]
+        );
+
+        let options = ActionOptions {
+            layout: Layout::Dvorak,
+            type_delay_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            action_to_events(&parse_expr(r#"type "s" + wait 5 + enter"#).unwrap(), &options),
+            vec![
+                (0, event(UKey::SemiColon, Action::Press)),
+                (0, event(UKey::SemiColon, Action::Release)),
+                (5, event(UKey::Enter, Action::Press)),
+                (5, event(UKey::Enter, Action::Release)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_action_unicode() {
+        let event = |key: UKey, action| Event {
+            key: key.into(),
+            action,
+        };
+        let options = ActionOptions {
+            type_delay_ms: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            action_to_events(&parse_expr("unicode U+00E9").unwrap(), &options),
+            vec![
+                (0, event(UKey::LeftControl, Action::Press)),
+                (0, event(UKey::LeftShift, Action::Press)),
+                (0, event(UKey::U, Action::Press)),
+                (0, event(UKey::U, Action::Release)),
+                (0, event(UKey::LeftShift, Action::Release)),
+                (0, event(UKey::LeftControl, Action::Release)),
+                (1, event(UKey::E, Action::Press)),
+                (1, event(UKey::E, Action::Release)),
+                (2, event(UKey::_9, Action::Press)),
+                (2, event(UKey::_9, Action::Release)),
+                (3, event(UKey::Space, Action::Press)),
+                (3, event(UKey::Space, Action::Release)),
+            ]
+        );
+
+        let options = ActionOptions {
+            type_delay_ms: 1,
+            unicode_method: UnicodeMethod::Compose,
+            compose_key: Key::Keyboard(UKey::RightMeta),
+            ..Default::default()
+        };
+        assert_eq!(
+            action_to_events(&parse_expr(r#"unicode "→" + a"#).unwrap(), &options),
+            vec![
+                (0, event(UKey::RightMeta, Action::Press)),
+                (0, event(UKey::RightMeta, Action::Release)),
+                (1, event(UKey::Minus, Action::Press)),
+                (1, event(UKey::Minus, Action::Release)),
+                (2, event(UKey::LeftShift, Action::Press)),
+                (2, event(UKey::Dot, Action::Press)),
+                (2, event(UKey::Dot, Action::Release)),
+                (2, event(UKey::LeftShift, Action::Release)),
+                (3, event(UKey::A, Action::Press)),
+                (3, event(UKey::A, Action::Release)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_action_exec() {
+        let mut options = ActionOptions::default();
+        options.exec.user = Some("alice".to_string());
+        options
+            .exec
+            .env
+            .insert("DISPLAY".to_string(), ":0".to_string());
+        let outputs = action_to_outputs(
+            &parse_expr(r#"a + wait 10 + exec "notify-send hi" + exec_as bob "true""#).unwrap(),
+            &options,
+        );
+        assert_eq!(outputs.len(), 4);
+        assert_eq!(
+            outputs[2],
+            (
+                10,
+                Output::Exec(ExecCommand {
+                    command: "notify-send hi".to_string(),
+                    user: "alice".to_string(),
+                    env: vec![("DISPLAY".to_string(), ":0".to_string())],
+                })
+            )
+        );
+        assert!(matches!(&outputs[3].1, Output::Exec(e) if e.user == "bob"));
+        // Only keys are left as events
+        assert_eq!(
+            action_to_events(&parse_expr(r#"a + exec "true""#).unwrap(), &options).len(),
+            2
+        );
+        // Without a user the command is skipped instead of run as root
+        options.exec.user = None;
+        assert_eq!(
+            action_to_outputs(&parse_expr(r#"exec "true""#).unwrap(), &options),
+            vec![]
+        );
+    }
 }
@@ -0,0 +1,117 @@
+use serde::Deserialize;
+
+use crate::key_buffer::UKey;
+
+// Physical keys of the main block, in the order of the layout strings below
+const KEYS: [UKey; 47] = [
+    UKey::Grave,
+    UKey::_1,
+    UKey::_2,
+    UKey::_3,
+    UKey::_4,
+    UKey::_5,
+    UKey::_6,
+    UKey::_7,
+    UKey::_8,
+    UKey::_9,
+    UKey::_0,
+    UKey::Minus,
+    UKey::Equal,
+    UKey::Q,
+    UKey::W,
+    UKey::E,
+    UKey::R,
+    UKey::T,
+    UKey::Y,
+    UKey::U,
+    UKey::I,
+    UKey::O,
+    UKey::P,
+    UKey::LeftBrace,
+    UKey::RightBrace,
+    UKey::BackSlash,
+    UKey::A,
+    UKey::S,
+    UKey::D,
+    UKey::F,
+    UKey::G,
+    UKey::H,
+    UKey::J,
+    UKey::K,
+    UKey::L,
+    UKey::SemiColon,
+    UKey::Apostrophe,
+    UKey::Z,
+    UKey::X,
+    UKey::C,
+    UKey::V,
+    UKey::B,
+    UKey::N,
+    UKey::M,
+    UKey::Comma,
+    UKey::Dot,
+    UKey::Slash,
+];
+
+const US: (&str, &str) = (
+    "`1234567890-=qwertyuiop[]\\asdfghjkl;'zxcvbnm,./",
+    "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"ZXCVBNM<>?",
+);
+
+const DVORAK: (&str, &str) = (
+    "`1234567890[]',.pyfgcrl/=\\aoeuidhtns-;qjkxbmwvz",
+    "~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_:QJKXBMWVZ",
+);
+
+/// Keyboard layout the desktop is set to, used to find the keys that type
+/// a character
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Us,
+    Dvorak,
+}
+
+impl Layout {
+    /// Key typing `c` and whether shift has to be held for it
+    pub fn key_for(&self, c: char) -> Option<(UKey, bool)> {
+        match c {
+            ' ' => return Some((UKey::Space, false)),
+            '\n' => return Some((UKey::Enter, false)),
+            '\t' => return Some((UKey::Tab, false)),
+            _ => {}
+        }
+        let (plain, shifted) = match self {
+            Layout::Us => US,
+            Layout::Dvorak => DVORAK,
+        };
+        if let Some(idx) = plain.chars().position(|p| p == c) {
+            return Some((KEYS[idx], false));
+        }
+        shifted
+            .chars()
+            .position(|s| s == c)
+            .map(|idx| (KEYS[idx], true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_key_for() {
+        assert_eq!(Layout::Us.key_for('h'), Some((UKey::H, false)));
+        assert_eq!(Layout::Us.key_for('H'), Some((UKey::H, true)));
+        assert_eq!(Layout::Us.key_for('!'), Some((UKey::_1, true)));
+        assert_eq!(Layout::Us.key_for('"'), Some((UKey::Apostrophe, true)));
+        assert_eq!(Layout::Us.key_for('\\'), Some((UKey::BackSlash, false)));
+        assert_eq!(Layout::Us.key_for(' '), Some((UKey::Space, false)));
+        assert_eq!(Layout::Us.key_for('é'), None);
+
+        assert_eq!(Layout::Dvorak.key_for('o'), Some((UKey::S, false)));
+        assert_eq!(Layout::Dvorak.key_for('W'), Some((UKey::Comma, true)));
+        assert_eq!(Layout::Dvorak.key_for('-'), Some((UKey::Apostrophe, false)));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+
+use super::KeyCombinationHashed;
+use super::parser::Expr;
+use crate::key_buffer::{Action, Event, KeyDeque};
+
+/// Presses and keys of every binding, to tell whether the buffered events
+/// can still become one of them.
+///
+/// Presses may come in any order, like `get_binding` matches them, but must
+/// all be presses of the binding. Releases must belong to the binding and
+/// come after the press of the same key, if the binding has one. Once no
+/// binding is left the buffered events can be released right away instead
+/// of waiting for the `delay_ms` timeout.
+#[derive(Debug)]
+pub struct Matcher {
+    presses: Vec<HashSet<u64>>,
+    hashes: Vec<HashSet<u64>>,
+}
+
+impl Matcher {
+    pub fn new(combinations: &[KeyCombinationHashed]) -> Self {
+        let mut presses = Vec::with_capacity(combinations.len());
+        let mut hashes = Vec::with_capacity(combinations.len());
+        for combo in combinations {
+            let keys = combo.combinations.combination.iter().filter_map(|expr| match expr {
+                Expr::Key(k) if k.action != Some(Action::Release) => Some(k.key),
+                _ => None,
+            });
+            presses.push(
+                keys.map(|key| {
+                    Event {
+                        key,
+                        action: Action::Press,
+                    }
+                    .get_u64_hash()
+                })
+                .collect(),
+            );
+            hashes.push(combo.keys_hashes.as_ref().clone());
+        }
+        Matcher { presses, hashes }
+    }
+
+    /// Indexes of the bindings the buffered events can still complete
+    pub fn candidates(&self, deq: &KeyDeque) -> Vec<usize> {
+        let mut pressed = HashSet::<u64>::new();
+        // Release hashes with the hash of the same key press, unless it was
+        // pressed earlier in the buffer
+        let mut releases = Vec::<(u64, Option<u64>)>::new();
+        for be in deq.iter() {
+            let hash = be.event.get_u64_hash();
+            match be.event.action {
+                // No binding presses a key twice
+                Action::Press if !pressed.insert(hash) => return Vec::new(),
+                Action::Press => {}
+                Action::Release => {
+                    let press = Event {
+                        key: be.event.key,
+                        action: Action::Press,
+                    }
+                    .get_u64_hash();
+                    releases.push((hash, (!pressed.contains(&press)).then_some(press)));
+                }
+            }
+        }
+        (0..self.presses.len())
+            .filter(|b| {
+                let keys = &self.hashes[*b];
+                pressed.is_subset(&self.presses[*b])
+                    && releases.iter().all(|(release, press)| {
+                        keys.contains(release) && press.is_none_or(|p| !keys.contains(&p))
+                    })
+            })
+            .collect()
+    }
+
+    /// Whether some enabled binding can still be completed by the buffered
+    /// events
+    pub fn can_match(&self, deq: &KeyDeque, enabled: impl Fn(usize) -> bool) -> bool {
+        deq.is_empty() || self.candidates(deq).into_iter().any(enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config_from_str;
+    use crate::key_buffer::{Action, BufferEvent, Event, KeyDeque, UKey};
+
+    macro_rules! events_deque {
+        ( $( ( $key:expr, $action:expr ) ),* $(,)? ) => {{
+            let mut deq = KeyDeque::new();
+            $(
+                deq.push_back(BufferEvent {
+                    event: Event {
+                        key: $key.into(),
+                        action: $action,
+                    },
+                    guard: None,
+                    read_at: std::time::Instant::now(),
+                });
+            )*
+            deq
+        }};
+    }
+
+    #[test]
+    fn test_matcher_candidates() {
+        let config = config_from_str(
+            r#"
+            [main]
+            "leftmeta + leftshift + F23" = "leftctrl down + wait 500 + leftctrl up"
+            "leftmeta down + l down" = "b"
+            "a down + b up + c up" = "b"
+            "#,
+        );
+        let matcher = &config.matcher;
+
+        let deq = events_deque!((UKey::LeftMeta, Action::Press));
+        assert_eq!(matcher.candidates(&deq).len(), 2);
+        assert!(matcher.can_match(&deq, |_| true));
+
+        let deq = events_deque!(
+            (UKey::LeftMeta, Action::Press),
+            (UKey::LeftShift, Action::Press),
+        );
+        assert_eq!(matcher.candidates(&deq).len(), 1);
+
+        let deq = events_deque!(
+            (UKey::LeftMeta, Action::Press),
+            (UKey::LeftShift, Action::Press),
+            (UKey::F23, Action::Press),
+            (UKey::F23, Action::Release),
+            (UKey::LeftMeta, Action::Release),
+        );
+        assert_eq!(matcher.candidates(&deq).len(), 1);
+
+        // Presses may come in any order
+        let deq = events_deque!(
+            (UKey::F23, Action::Press),
+            (UKey::LeftShift, Action::Press),
+        );
+        assert_eq!(matcher.candidates(&deq).len(), 1);
+
+        // Not a press of the binding
+        let deq = events_deque!(
+            (UKey::LeftShift, Action::Press),
+            (UKey::L, Action::Press),
+        );
+        assert!(!matcher.can_match(&deq, |_| true));
+
+        // "leftmeta up" isn't part of the "l" binding
+        let deq = events_deque!(
+            (UKey::LeftMeta, Action::Press),
+            (UKey::L, Action::Press),
+            (UKey::LeftMeta, Action::Release),
+        );
+        assert!(!matcher.can_match(&deq, |_| true));
+
+        // Release of a key the binding presses, without the press
+        let deq = events_deque!((UKey::F23, Action::Release));
+        assert!(!matcher.can_match(&deq, |_| true));
+
+        // Releases the binding expects on their own
+        let deq = events_deque!((UKey::C, Action::Release), (UKey::A, Action::Press));
+        assert_eq!(matcher.candidates(&deq).len(), 1);
+
+        let deq = events_deque!((UKey::Z, Action::Press));
+        assert!(!matcher.can_match(&deq, |_| true));
+        assert!(matcher.can_match(&KeyDeque::new(), |_| true));
+    }
+}
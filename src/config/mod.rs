@@ -1,27 +1,191 @@
 #![allow(dead_code)]
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use crate::config::parser::Expr;
-use crate::key_buffer::{Action, Event};
-pub use config_processor::{get_action, action_to_events};
+use crate::key_buffer::{Action, Event, Key, KeyDeque, UKey};
+use crate::log_warn;
+#[allow(unused_imports)]
+pub use config_processor::{action_to_events, get_action};
+pub use config_processor::{ActionOptions, ExecConfig, Output, action_to_outputs};
+use config_processor::get_binding;
+pub use layout::Layout;
+pub use mouse_keys::{MouseKeyAction, MouseKeysConfig};
+use mouse_keys::{RawMouseKeys, parse_mouse_keys};
+pub use unicode::UnicodeMethod;
+use matcher::Matcher;
 pub use parser::Expressions;
-use parser::{parse_expr};
+pub use parser::parse_key_sequence;
+use parser::{parse_expr, to_key};
+use self_test::TestCase;
+use unicode::compose_sequence;
+pub use self_test::{Timeline, format_timeline, run_config_tests, simulate};
 use serde::Deserialize;
 use toml::Table;
 
 mod config_processor;
+mod layout;
+mod matcher;
+mod mouse_keys;
 mod parser;
+mod self_test;
+mod unicode;
 
 #[derive(Deserialize, Debug)]
-struct Config {
+pub struct Config {
     delay_ms: Option<u64>,
+    max_scheduled: Option<usize>,
+    layout: Option<Layout>,
+    type_delay_ms: Option<u64>,
+    unicode_method: Option<UnicodeMethod>,
+    compose_key: Option<String>,
+    pointer_devices: Option<Vec<String>>,
+    socket_group: Option<String>,
+    on_shutdown: Option<ShutdownPolicy>,
+    shutdown_flush_ms: Option<u64>,
+    watchdog_ms: Option<u64>,
+    log: Option<String>,
+    bypass: Option<RawBypass>,
+    #[serde(default)]
+    emergency_exit: RawEmergencyExit,
+    mouse_keys: Option<RawMouseKeys>,
+    #[serde(default)]
+    exec: ExecConfig,
     main: Table,
+    /// Bindings that only apply while their layer is switched on
+    #[serde(default)]
+    layers: BTreeMap<String, Table>,
+    #[serde(default)]
+    test: Vec<TestCase>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+struct RawBypass {
+    keys: String,
+    timeout_s: Option<u64>,
+}
+
+/// Keys held together that switch all remapping off and back on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bypass {
+    pub keys: Vec<Key>,
+    /// Remapping comes back on its own after this long
+    pub timeout_ms: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+struct RawEmergencyExit {
+    keys: Option<String>,
+    presses: Option<u32>,
+    window_ms: Option<u64>,
+}
+
+/// Keys held together `presses` times within `window_ms` ungrab the
+/// keyboard and stop kbd. There is no way to turn it off, a config that
+/// can't be used falls back to the default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmergencyExit {
+    pub keys: Vec<Key>,
+    pub presses: u32,
+    pub window_ms: i64,
+}
+
+impl Default for EmergencyExit {
+    fn default() -> Self {
+        EmergencyExit {
+            keys: vec![Key::Keyboard(UKey::BackSpace), Key::Keyboard(UKey::Esc)],
+            presses: 3,
+            window_ms: 2000,
+        }
+    }
+}
+
+fn parse_emergency_exit(raw: &RawEmergencyExit) -> EmergencyExit {
+    let default = EmergencyExit::default();
+    let keys = raw.keys.as_ref().map(|keys| {
+        keys.split('+')
+            .map(|key| to_key(&key.trim().to_lowercase()))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    let keys = match keys {
+        None => default.keys,
+        Some(Ok(keys)) if !keys.is_empty() => keys,
+        Some(_) => {
+            log_warn!(
+                "Can't use emergency_exit keys {:?}, keeping backspace + esc",
+                raw.keys
+            );
+            default.keys
+        }
+    };
+    EmergencyExit {
+        keys,
+        presses: raw.presses.unwrap_or(default.presses).max(1),
+        window_ms: raw.window_ms.map_or(default.window_ms, |ms| ms.max(1) as i64),
+    }
+}
+
+/// What happens when a binding fires while its previous action still runs
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Retrigger {
+    /// Run another copy alongside the first one
+    #[default]
+    Overlap,
+    /// Cancel the running action and start over
+    Restart,
+    /// Keep the running action, drop the new one
+    Ignore,
+}
+
+/// What becomes of scheduled actions when kbd is stopped
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownPolicy {
+    /// Let them finish, for `shutdown_flush_ms` at most. Repeating ones
+    /// never finish and are cancelled.
+    #[default]
+    Flush,
+    /// Drop them, keys they hold are released
+    Cancel,
+}
+
+const SHUTDOWN_FLUSH_MS: u64 = 1000;
+const WATCHDOG_MS: u64 = 2000;
+// Below this a busy moment passes for a stall
+const MIN_WATCHDOG_MS: u64 = 100;
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct BindingOptions {
+    #[serde(default)]
+    pub retrigger: Retrigger,
+    /// Any key press cancels the running action
+    #[serde(default)]
+    pub cancel_on_keypress: bool,
+    /// Run the action again at this interval until a trigger key goes up
+    pub repeat_ms: Option<u64>,
+}
+
+/// Binding value, either the action alone or a table with options:
+/// `"a" = { action = "b + wait 500 + c", retrigger = "restart" }`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Binding {
+    Action(String),
+    Table {
+        action: String,
+        #[serde(flatten)]
+        options: BindingOptions,
+    },
 }
 
 #[derive(Debug)]
 pub struct KeyCombination {
+    // Trigger and action as written in the config
+    text: (String, String),
+    layer: Option<String>,
     combination: Expressions,
     action: Expressions,
+    options: BindingOptions,
 }
 
 #[derive(Debug)]
@@ -31,11 +195,28 @@ pub struct KeyCombinationHashed {
 }
 
 type KeyHashes = HashSet<u64>;
+/// Names of the layers switched on
+pub type Layers = HashSet<String>;
 #[derive(Debug)]
 pub struct ParsedConfig {
     pub delay_ms: Option<u64>,
+    pub max_scheduled: Option<usize>,
+    pub action_options: ActionOptions,
+    pub pointer_devices: Option<Vec<String>>,
+    /// Group allowed on the control socket besides root
+    pub socket_group: Option<String>,
+    pub on_shutdown: ShutdownPolicy,
+    pub shutdown_flush_ms: u64,
+    /// How long an event may be stuck before the keyboard is let go
+    pub watchdog_ms: u64,
+    /// Log filter, "info" or "warn,key_buffer=trace"
+    pub log: Option<String>,
+    pub bypass: Option<Bypass>,
+    pub emergency_exit: EmergencyExit,
+    pub mouse_keys: Option<MouseKeysConfig>,
     pub key_combinations: Vec<KeyCombinationHashed>,
     combo_hashes: Box<KeyHashes>,
+    matcher: Matcher,
 }
 
 impl ParsedConfig {
@@ -43,91 +224,268 @@ impl ParsedConfig {
         let hash = event.get_u64_hash();
         self.combo_hashes.contains(&hash)
     }
+
+    pub fn action(&self, binding: usize) -> &Expressions {
+        &self.key_combinations[binding].combinations.action
+    }
+
+    pub fn binding_options(&self, binding: usize) -> &BindingOptions {
+        &self.key_combinations[binding].combinations.options
+    }
+
+    /// Whether the binding's trigger holds `key` down
+    pub fn binding_presses(&self, binding: usize, key: Key) -> bool {
+        self.key_combinations[binding]
+            .combinations
+            .combination
+            .iter()
+            .any(|e| matches!(e, Expr::Key(k) if k.key == key && k.action != Some(Action::Release)))
+    }
+
+    /// Layer, trigger and action of every binding, as written in the config
+    pub fn bindings(&self) -> impl Iterator<Item = (Option<&str>, &str, &str)> {
+        self.key_combinations.iter().map(|combo| {
+            let (trigger, action) = &combo.combinations.text;
+            (
+                combo.combinations.layer.as_deref(),
+                trigger.as_str(),
+                action.as_str(),
+            )
+        })
+    }
+
+    pub fn has_layer(&self, name: &str) -> bool {
+        self.bindings().any(|(layer, _, _)| layer == Some(name))
+    }
+
+    /// Whether the binding applies with `layers` switched on
+    pub fn enabled(&self, binding: usize, layers: &Layers) -> bool {
+        self.key_combinations[binding]
+            .combinations
+            .layer
+            .as_ref()
+            .is_none_or(|layer| layers.contains(layer))
+    }
+
+    /// First binding the buffered events complete
+    pub fn binding(&self, deq: &KeyDeque, layers: &Layers) -> Option<usize> {
+        get_binding(deq, &self.key_combinations, |b| self.enabled(b, layers))
+    }
+
+    /// Whether some binding is triggered by a mouse button or the wheel
+    pub fn uses_pointer(&self) -> bool {
+        self.key_combinations.iter().any(|combo| {
+            combo.combinations.combination.iter().any(|expr| {
+                matches!(expr, Expr::Key(k) if matches!(k.key, Key::Button(_) | Key::Scroll(..)))
+            })
+        })
+    }
+
+    /// Whether the buffered events are still a prefix of some binding
+    pub fn can_match(&self, deq: &KeyDeque, layers: &Layers) -> bool {
+        self.matcher.can_match(deq, |b| self.enabled(b, layers))
+    }
+}
+
+#[cfg(debug_assertions)]
+pub const CONFIG_PATH: &str = "config.toml";
+#[cfg(not(debug_assertions))]
+pub const CONFIG_PATH: &str = "/etc/kbd/config.toml";
+
+pub fn read_config() -> Result<Config, String> {
+    _read_config(CONFIG_PATH)
 }
 
-#[allow(dead_code)]
-fn read_config() -> Result<Config, &'static str> {
-    #[cfg(debug_assertions)]
-    let config_path = "config.toml";
-    #[cfg(not(debug_assertions))]
-    let config_path = "/etc/kbd/config.toml";
-    _read_config(config_path)
+pub fn load_config() -> Result<ParsedConfig, String> {
+    try_load_config(CONFIG_PATH)
 }
 
-pub fn load_config() -> ParsedConfig {
+/// Reads and parses the config at `path`, a running daemon keeps its
+/// current config when this fails
+pub fn try_load_config(path: &str) -> Result<ParsedConfig, String> {
+    _parse_config(&_read_config(path)?)
+}
 
-    let raw_config = read_config().unwrap();
-    _parse_config(&raw_config)
+/// Whether a key of the trigger is still down once it fired
+fn holds_key(condition: &[Expr]) -> bool {
+    let keys = || {
+        condition.iter().filter_map(|e| match e {
+            Expr::Key(k) => Some(k),
+            _ => None,
+        })
+    };
+    keys().any(|held| {
+        held.action == Some(Action::Press)
+            && !held.key.is_relative()
+            && keys().all(|k| k.key != held.key || k.action == Some(Action::Press))
+    })
 }
 
-fn _parse_config(config: &Config) -> ParsedConfig {
+fn _parse_config(config: &Config) -> Result<ParsedConfig, String> {
+    let layout = config.layout.unwrap_or_default();
     let mut combos = Vec::<KeyCombinationHashed>::new();
     let mut total_hashes = Box::new(KeyHashes::new());
-    for (k, v) in config.main.iter() {
+    // Layers go first, their bindings win over the ones of [main]
+    let tables = config
+        .layers
+        .iter()
+        .map(|(name, table)| (Some(name), table))
+        .chain([(None, &config.main)]);
+    for (layer, k, v) in tables.flat_map(|(layer, t)| t.iter().map(move |(k, v)| (layer, k, v))) {
         let mut key_events = Box::new(KeyHashes::new());
-        if let toml::Value::String(v) = v {
-            let parsed_condition = parse_expr(k);
-
-            for c in &parsed_condition {
-                if let Expr::Key(k) = c {
-                    match &k.action {
-                        None => {
-                            let hash = Event {
-                                key: k.key,
-                                action: Action::Press,
-                            }
-                            .get_u64_hash();
-
-                            key_events.insert(hash);
-                            total_hashes.insert(hash);
-                            let hash = Event {
-                                key: k.key,
-                                action: Action::Release,
-                            }
-                            .get_u64_hash();
-                            key_events.insert(hash);
-                            total_hashes.insert(hash);
+        let (v, options) = match v.clone().try_into::<Binding>() {
+            Ok(Binding::Action(action)) => (action, BindingOptions::default()),
+            Ok(Binding::Table { action, options }) => (action, options),
+            Err(e) => {
+                return Err(format!(
+                    "Expected an action string or table for key '{}', but found {:?}: {}",
+                    k, v, e
+                ));
+            }
+        };
+        let parsed_condition = parse_expr(k).map_err(|e| format!("Bad binding '{k}': {e}"))?;
+
+        for c in &parsed_condition {
+            if let Expr::Key(k) = c {
+                match &k.action {
+                    None => {
+                        let hash = Event {
+                            key: k.key,
+                            action: Action::Press,
+                        }
+                        .get_u64_hash();
+
+                        key_events.insert(hash);
+                        total_hashes.insert(hash);
+                        let hash = Event {
+                            key: k.key,
+                            action: Action::Release,
                         }
-                        Some(action) => {
-                            let hash = Event {
-                                key: k.key,
-                                action: action.clone(),
-                            }
-                            .get_u64_hash();
-                            key_events.insert(hash);
-                            total_hashes.insert(hash);
+                        .get_u64_hash();
+                        key_events.insert(hash);
+                        total_hashes.insert(hash);
+                    }
+                    Some(action) => {
+                        let hash = Event {
+                            key: k.key,
+                            action: *action,
                         }
+                        .get_u64_hash();
+                        key_events.insert(hash);
+                        total_hashes.insert(hash);
                     }
                 }
             }
+        }
 
-            let parsed_action = parse_expr(v);
-            combos.push(KeyCombinationHashed {
-                combinations: KeyCombination {
-                    combination: parsed_condition,
-                    action: parsed_action,
-                },
-                keys_hashes: key_events,
-            });
-        } else {
-            panic!("Expected a string value for key '{}', but found {:?}", k, v)
+        if let Some(interval) = options.repeat_ms {
+            if interval == 0 {
+                return Err(format!("repeat_ms of '{}' has to be above 0", k));
+            }
+            // Repeats stop when a held trigger key goes up, a trigger that
+            // fires on release would repeat forever
+            if !holds_key(&parsed_condition) {
+                return Err(format!(
+                    "repeat_ms of '{}' needs a key held with down, nothing would stop it",
+                    k
+                ));
+            }
         }
+
+        let parsed_action = parse_expr(&v).map_err(|e| format!("Bad action of '{k}': {e}"))?;
+        for expr in &parsed_action {
+            if let Expr::Type(t) = expr
+                && let Some(c) = t.text.chars().find(|c| layout.key_for(*c).is_none())
+            {
+                return Err(format!(
+                    "Can't type {:?} of '{}' with the {:?} layout",
+                    c, k, layout
+                ));
+            }
+            if let Expr::Exec(e) = expr
+                && e.user.is_none()
+                && config.exec.user.is_none()
+            {
+                return Err(format!(
+                    "exec of '{}' has no user, set [exec] user or use exec_as",
+                    k
+                ));
+            }
+            if let Expr::Unicode(u) = expr
+                && config.unicode_method == Some(UnicodeMethod::Compose)
+                && let Some(c) = u.text.chars().find(|c| compose_sequence(*c).is_none())
+            {
+                return Err(format!("No compose sequence for {:?} of '{}'", c, k));
+            }
+        }
+        combos.push(KeyCombinationHashed {
+            combinations: KeyCombination {
+                text: (k.clone(), v.clone()),
+                layer: layer.cloned(),
+                combination: parsed_condition,
+                action: parsed_action,
+                options,
+            },
+            keys_hashes: key_events,
+        });
     }
 
-    ParsedConfig {
+    let compose_key = match &config.compose_key {
+        Some(key) => to_key(&key.to_lowercase()).map_err(|e| format!("Bad compose_key: {e}"))?,
+        None => ActionOptions::default().compose_key,
+    };
+    if let Some(spec) = &config.log {
+        crate::log::Filter::parse(spec).map_err(|e| format!("Bad log filter: {e}"))?;
+    }
+    let bypass = match &config.bypass {
+        Some(raw) => Some(Bypass {
+            keys: raw
+                .keys
+                .split('+')
+                .map(|key| to_key(&key.trim().to_lowercase()))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("Bad bypass keys: {e}"))?,
+            timeout_ms: raw.timeout_s.map(|s| s as i64 * 1000),
+        }),
+        None => None,
+    };
+    let mouse_keys = config.mouse_keys.as_ref().map(parse_mouse_keys).transpose()?;
+
+    let matcher = Matcher::new(&combos);
+    Ok(ParsedConfig {
         delay_ms: config.delay_ms,
+        max_scheduled: config.max_scheduled,
+        action_options: ActionOptions {
+            layout,
+            type_delay_ms: config
+                .type_delay_ms
+                .unwrap_or(ActionOptions::default().type_delay_ms),
+            unicode_method: config.unicode_method.unwrap_or_default(),
+            compose_key,
+            exec: config.exec.clone(),
+        },
+        pointer_devices: config.pointer_devices.clone(),
+        socket_group: config.socket_group.clone(),
+        on_shutdown: config.on_shutdown.unwrap_or_default(),
+        shutdown_flush_ms: config.shutdown_flush_ms.unwrap_or(SHUTDOWN_FLUSH_MS),
+        watchdog_ms: config
+            .watchdog_ms
+            .unwrap_or(WATCHDOG_MS)
+            .max(MIN_WATCHDOG_MS),
+        log: config.log.clone(),
+        bypass,
+        emergency_exit: parse_emergency_exit(&config.emergency_exit),
+        mouse_keys,
         key_combinations: combos,
         combo_hashes: total_hashes,
-    }
+        matcher,
+    })
 }
 
-fn _read_config(path: &str) -> Result<Config, &'static str> {
-    let config_str = std::fs::read_to_string(path).expect("Failed to read config.toml");
-    if let Ok(config) = toml::from_str(config_str.as_str()) {
-        Ok(config)
-    } else {
-        Err("Failed to parse config file")
-    }
+fn _read_config(path: &str) -> Result<Config, String> {
+    let config_str =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    toml::from_str(config_str.as_str()).map_err(|e| format!("Failed to parse {path}: {e}"))
 }
 
 #[cfg(test)]
@@ -135,7 +493,7 @@ pub fn config_from_str(s: &str) -> ParsedConfig {
     use crate::config::_parse_config;
     use crate::config::Config;
     let raw_config: Config = toml::from_str(s).unwrap();
-    _parse_config(&raw_config)
+    _parse_config(&raw_config).unwrap()
 }
 
 #[cfg(test)]
@@ -153,7 +511,7 @@ mod tests {
             "Config 'main' does not contain the expected key"
         );
         if let Some(toml::Value::String(v)) = config.main.get(expected_key) {
-            assert_eq!(v, "leftctrl down + wait 500 + leftctrl up");
+            assert_eq!(v, "leftctrl down + wait 300 + leftctrl up");
         }
     }
     #[test]
@@ -171,39 +529,133 @@ mod tests {
         assert_eq!(parsed_config.delay_ms, Some(5));
 
         assert!(parsed_config.has_key(&Event {
-            key: UKey::LeftShift,
+            key: Key::Keyboard(UKey::LeftShift),
             action: Action::Press
         }));
         assert!(parsed_config.has_key(&Event {
-            key: UKey::LeftShift,
+            key: Key::Keyboard(UKey::LeftShift),
             action: Action::Release
         }));
         assert!(parsed_config.has_key(&Event {
-            key: UKey::F23,
+            key: Key::Keyboard(UKey::F23),
             action: Action::Press
         }));
         assert!(parsed_config.has_key(&Event {
-            key: UKey::F23,
+            key: Key::Keyboard(UKey::F23),
             action: Action::Release
         }));
         assert!(parsed_config.has_key(&Event {
-            key: UKey::A,
+            key: Key::Keyboard(UKey::A),
             action: Action::Press
         }));
         assert!(parsed_config.has_key(&Event {
-            key: UKey::A,
+            key: Key::Keyboard(UKey::A),
             action: Action::Release
         }));
-        assert_eq!(
-            parsed_config.has_key(&Event {
-                key: UKey::F24,
+        assert!(
+            !parsed_config.has_key(&Event {
+                key: Key::Keyboard(UKey::F24),
                 action: Action::Release
-            }),
-            false
+            })
         );
         println!("parsed {parsed_config:?}");
     }
 
+    #[test]
+    fn test_config_binding_options() {
+        let parsed_config = config_from_str(
+            r#"
+            [main]
+            "a" = "b"
+            "c" = { action = "d + wait 100 + e", retrigger = "restart" }
+            "f" = { action = "g", cancel_on_keypress = true }
+            "leftctrl + h down" = { action = "i", repeat_ms = 40 }
+            "#,
+        );
+        let options = |key: UKey| {
+            let idx = parsed_config
+                .key_combinations
+                .iter()
+                .position(|c| {
+                    c.combinations.combination
+                        == vec![Expr::Key(parser::KeyExpr {
+                            key: key.into(),
+                            action: None,
+                        })]
+                })
+                .unwrap();
+            parsed_config.binding_options(idx).clone()
+        };
+        assert_eq!(options(UKey::A), BindingOptions::default());
+        assert_eq!(options(UKey::C).retrigger, Retrigger::Restart);
+        assert!(!options(UKey::C).cancel_on_keypress);
+        assert_eq!(options(UKey::F).retrigger, Retrigger::Overlap);
+        assert!(options(UKey::F).cancel_on_keypress);
+        assert_eq!(options(UKey::F).repeat_ms, None);
+
+        let idx = parsed_config
+            .key_combinations
+            .iter()
+            .position(|c| c.combinations.options.repeat_ms == Some(40))
+            .unwrap();
+        assert!(parsed_config.binding_presses(idx, UKey::LeftControl.into()));
+        assert!(parsed_config.binding_presses(idx, UKey::H.into()));
+        assert!(!parsed_config.binding_presses(idx, UKey::I.into()));
+    }
+
+    #[test]
+    fn test_config_emergency_exit() {
+        let parsed_config = config_from_str("[main]\n\"a\" = \"b\"\n");
+        assert_eq!(parsed_config.emergency_exit, EmergencyExit::default());
+
+        let parsed_config = config_from_str(
+            r#"
+            [emergency_exit]
+            keys = "leftctrl + rightctrl + f12"
+            presses = 0
+            [main]
+            "#,
+        );
+        let exit = parsed_config.emergency_exit;
+        assert_eq!(exit.keys.len(), 3);
+        assert_eq!(exit.presses, 1);
+        assert_eq!(exit.window_ms, 2000);
+
+        // Keys that can't be pressed don't turn it off
+        for keys in ["", "nokey + esc"] {
+            let parsed_config =
+                config_from_str(&format!("[main]\n[emergency_exit]\nkeys = \"{keys}\"\n"));
+            assert_eq!(parsed_config.emergency_exit.keys, EmergencyExit::default().keys);
+        }
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let parse = |s: &str| _parse_config(&toml::from_str(s).unwrap());
+        for config in [
+            "[main]\n\"nokey\" = \"b\"\n",
+            "[main]\n\"a\" = \"b + wait soon\"\n",
+            "[main]\n\"a\" = 5\n",
+            "layout = \"us\"\n[main]\n\"a\" = 'type \"ü\"'\n",
+            "compose_key = \"nokey\"\n[main]\n",
+            "log = \"loud\"\n[main]\n",
+            "[bypass]\nkeys = \"f12 + nokey\"\n[main]\n",
+            "[mouse_keys]\nlayer = \"nokey\"\n[main]\n",
+            // Would run as root
+            "[main]\n\"a\" = 'exec \"true\"'\n",
+            "[main]\n\"a down\" = { action = \"b\", repeat_ms = 0 }\n",
+            // Fires on the release, nothing would stop the repeats
+            "[main]\n\"leftctrl + a\" = { action = \"b\", repeat_ms = 40 }\n",
+            "[main]\n\"a down + a up\" = { action = \"b\", repeat_ms = 40 }\n",
+        ] {
+            assert!(parse(config).is_err(), "{config}");
+        }
+        assert!(parse("[exec]\nuser = \"alice\"\n[main]\n\"a\" = 'exec \"true\"'\n").is_ok());
+        assert!(parse("[main]\n\"a\" = 'exec_as root \"true\"'\n").is_ok());
+        assert!(parse("[main]\n\"a down\" = { action = \"b\", repeat_ms = 40 }\n").is_ok());
+        assert!(try_load_config("/nonexistent/config.toml").is_err());
+    }
+
     #[test]
     #[ignore = "Rust playground"]
     fn test_something() {
@@ -219,7 +671,7 @@ mod tests {
                 }
             }
 
-            &s[..]
+            s
         }
         let inp = "leftctrl down + wait 500  + leftctrl up";
         let mut v = vec![1, 2, 3, 5];
@@ -238,7 +690,7 @@ mod tests {
         v.sort_by(|a, b| b.cmp(a));
         println!("{v:?}");
         // let novel = String::from("Call me Ishmael. Some years ago...");
-        fn fff(sss: &str) -> ImportantExcerpt {
+        fn fff(sss: &str) -> ImportantExcerpt<'_> {
             let first_sentence = sss.split(' ').nth(1).unwrap();
             let i = ImportantExcerpt {
                 part: first_sentence,
@@ -0,0 +1,157 @@
+use serde::Deserialize;
+
+use super::parser::to_key;
+use crate::key_buffer::{Axis, Key, UButton};
+
+/// `[mouse_keys]` section as written in the config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RawMouseKeys {
+    layer: String,
+    #[serde(default)]
+    keys: Keys,
+    tick_ms: Option<u64>,
+    start_speed: Option<f64>,
+    max_speed: Option<f64>,
+    accel_ms: Option<u64>,
+    accel_curve: Option<f64>,
+    scroll_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct Keys {
+    up: String,
+    down: String,
+    left: String,
+    right: String,
+    left_click: String,
+    right_click: String,
+    middle_click: String,
+    scroll_up: String,
+    scroll_down: String,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        let s = |s: &str| s.to_string();
+        Keys {
+            up: s("k"),
+            down: s("j"),
+            left: s("h"),
+            right: s("l"),
+            left_click: s("f"),
+            right_click: s("d"),
+            middle_click: s("s"),
+            scroll_up: s("u"),
+            scroll_down: s("n"),
+        }
+    }
+}
+
+/// What a key does while the mouse keys layer is held
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseKeyAction {
+    /// Unit direction, scaled by the speed on every tick
+    Move(i32, i32),
+    Click(UButton),
+    Scroll(Axis, i32),
+}
+
+/// Keys move the pointer while `layer` is held. The speed in pixels per
+/// tick goes from `start_speed` to `max_speed` over `accel_ms`, along
+/// `t^accel_curve` with `t` the share of `accel_ms` gone by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MouseKeysConfig {
+    pub layer: Key,
+    pub keys: Vec<(Key, MouseKeyAction)>,
+    pub tick_ms: u64,
+    pub start_speed: f64,
+    pub max_speed: f64,
+    pub accel_ms: u64,
+    pub accel_curve: f64,
+    /// Wheel click interval while a scroll key is held
+    pub scroll_ms: u64,
+}
+
+impl MouseKeysConfig {
+    pub fn action(&self, key: Key) -> Option<MouseKeyAction> {
+        self.keys.iter().find(|(k, _)| *k == key).map(|(_, a)| *a)
+    }
+
+    /// Pixels per tick once movement has been going on for `held_ms`
+    pub fn speed(&self, held_ms: i64) -> f64 {
+        let t = if self.accel_ms == 0 {
+            1.0
+        } else {
+            (held_ms.max(0) as f64 / self.accel_ms as f64).min(1.0)
+        };
+        self.start_speed + (self.max_speed - self.start_speed) * t.powf(self.accel_curve)
+    }
+}
+
+pub fn parse_mouse_keys(raw: &RawMouseKeys) -> Result<MouseKeysConfig, String> {
+    let key = |name: &str| {
+        to_key(&name.to_lowercase()).map_err(|e| format!("Bad key in [mouse_keys]: {e}"))
+    };
+    let k = &raw.keys;
+    let keys = vec![
+        (key(&k.up)?, MouseKeyAction::Move(0, -1)),
+        (key(&k.down)?, MouseKeyAction::Move(0, 1)),
+        (key(&k.left)?, MouseKeyAction::Move(-1, 0)),
+        (key(&k.right)?, MouseKeyAction::Move(1, 0)),
+        (key(&k.left_click)?, MouseKeyAction::Click(UButton::Left)),
+        (key(&k.right_click)?, MouseKeyAction::Click(UButton::Right)),
+        (key(&k.middle_click)?, MouseKeyAction::Click(UButton::Middle)),
+        (key(&k.scroll_up)?, MouseKeyAction::Scroll(Axis::Y, 1)),
+        (key(&k.scroll_down)?, MouseKeyAction::Scroll(Axis::Y, -1)),
+    ];
+    let tick_ms = raw.tick_ms.unwrap_or(16);
+    if tick_ms == 0 {
+        return Err("tick_ms of [mouse_keys] must be above 0".to_string());
+    }
+    Ok(MouseKeysConfig {
+        layer: key(&raw.layer)?,
+        keys,
+        tick_ms,
+        start_speed: raw.start_speed.unwrap_or(1.0),
+        max_speed: raw.max_speed.unwrap_or(20.0),
+        accel_ms: raw.accel_ms.unwrap_or(800),
+        accel_curve: raw.accel_curve.unwrap_or(2.0),
+        scroll_ms: raw.scroll_ms.unwrap_or(80).max(1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_buffer::UKey;
+
+    #[test]
+    fn test_parse_mouse_keys() {
+        let raw: RawMouseKeys = toml::from_str(
+            r#"
+            layer = "capslock"
+            keys = { left_click = "space" }
+            max_speed = 11
+            accel_ms = 100
+            "#,
+        )
+        .unwrap();
+        let config = parse_mouse_keys(&raw).unwrap();
+        assert_eq!(config.layer, UKey::CapsLock.into());
+        assert_eq!(
+            config.action(UKey::Space.into()),
+            Some(MouseKeyAction::Click(UButton::Left))
+        );
+        assert_eq!(
+            config.action(UKey::H.into()),
+            Some(MouseKeyAction::Move(-1, 0))
+        );
+        assert_eq!(config.action(UKey::F.into()), None);
+
+        assert_eq!(config.speed(0), 1.0);
+        assert_eq!(config.speed(50), 3.5);
+        assert_eq!(config.speed(100), 11.0);
+        assert_eq!(config.speed(5000), 11.0);
+    }
+}
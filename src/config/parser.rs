@@ -1,4 +1,5 @@
-use crate::key_buffer::{Action, UKey};
+use super::unicode::parse_code_point;
+use crate::key_buffer::{Action, Axis, Event, Key, UButton, UKey};
 
 impl Action {
     fn from_str(s: &str) -> Result<Action, String> {
@@ -10,7 +11,7 @@ impl Action {
     }
 }
 
-fn to_u_key(s: &str) -> Result<UKey, String> {
+pub(super) fn to_u_key(s: &str) -> Result<UKey, String> {
     match s {
         "a" => Ok(UKey::A),
         "b" => Ok(UKey::B),
@@ -105,9 +106,45 @@ fn to_u_key(s: &str) -> Result<UKey, String> {
     }
 }
 
+fn to_button(s: &str) -> Result<UButton, String> {
+    match s {
+        "left" => Ok(UButton::Left),
+        "right" => Ok(UButton::Right),
+        "middle" => Ok(UButton::Middle),
+        "side" => Ok(UButton::Side),
+        "extra" => Ok(UButton::Extra),
+        "forward" => Ok(UButton::Forward),
+        "back" => Ok(UButton::Back),
+        "task" => Ok(UButton::Task),
+        _ => Err(format!("Unknown mouse button {s}")),
+    }
+}
+
+/// Keyboard key or mouse button, buttons are `mouse_<name>` or `btn_<name>`
+pub(super) fn to_key(s: &str) -> Result<Key, String> {
+    if let Some(button) = s.strip_prefix("mouse_").or_else(|| s.strip_prefix("btn_")) {
+        return to_button(button).map(Key::Button);
+    }
+    to_u_key(s).map(Key::Keyboard)
+}
+
+/// Wheel clicks for `scroll <up|down|left|right> [n]`
+fn to_scroll(direction: &str, clicks: &str) -> Result<Key, String> {
+    let clicks = clicks
+        .parse::<i32>()
+        .map_err(|e| format!("Bad scroll amount {clicks}: {e}"))?;
+    match direction {
+        "up" => Ok(Key::Scroll(Axis::Y, clicks)),
+        "down" => Ok(Key::Scroll(Axis::Y, -clicks)),
+        "left" => Ok(Key::Scroll(Axis::X, -clicks)),
+        "right" => Ok(Key::Scroll(Axis::X, clicks)),
+        _ => Err(format!("Unknown scroll direction {direction}")),
+    }
+}
+
 #[derive(Debug, PartialEq, Hash)]
 pub struct KeyExpr {
-    pub key: UKey,
+    pub key: Key,
     pub action: Option<Action>,
 }
 #[derive(Debug, PartialEq)]
@@ -115,58 +152,263 @@ pub struct WaitExpr {
     pub milliseconds: u64,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct TypeExpr {
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnicodeExpr {
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExecExpr {
+    pub command: String,
+    // Runs as the `[exec]` user when not set
+    pub user: Option<String>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Expr {
     Key(KeyExpr),
     Wait(WaitExpr),
+    Type(TypeExpr),
+    Unicode(UnicodeExpr),
+    Exec(ExecExpr),
 }
 pub type Expressions = Vec<Expr>;
 
-pub fn parse_expr(input: &str) -> Vec<Expr> {
-    let input = input.to_lowercase();
+/// Splits on `+` outside of double quotes
+fn split_exprs(input: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            // Keep the plus of "unicode U+00E9"
+            '+' if !quoted && !input[start..idx].trim().eq_ignore_ascii_case("unicode u") => {
+                parts.push(&input[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Parses `<name> [arg] "<text>"`, the text keeps its case
+fn parse_quoted(name: &str, text: &str) -> Result<Expr, String> {
+    let Some(text) = text.strip_suffix('"') else {
+        return Err(format!("Unterminated string in {name} \"{text}"));
+    };
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.push(c),
+            None => return Err(format!("Dangling escape in \"{text}\"")),
+        }
+    }
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let kind = words.first().map(|w| w.to_lowercase()).unwrap_or_default();
+    Ok(match (kind.as_str(), &words[1..]) {
+        ("type", []) => Expr::Type(TypeExpr { text: unescaped }),
+        ("unicode", []) => Expr::Unicode(UnicodeExpr { text: unescaped }),
+        ("exec", []) => Expr::Exec(ExecExpr {
+            command: unescaped,
+            user: None,
+        }),
+        ("exec_as", [user]) => Expr::Exec(ExecExpr {
+            command: unescaped,
+            user: Some(user.to_string()),
+        }),
+        _ => return Err(format!("Unknown expression {name} \"{text}\"")),
+    })
+}
+
+pub fn parse_expr(input: &str) -> Result<Vec<Expr>, String> {
     let mut exprs = Vec::<Expr>::new();
-    for i in input.split('+') {
+    for i in split_exprs(input) {
         let i = i.trim();
+        if let Some((name, text)) = i.split_once('"') {
+            exprs.push(parse_quoted(name.trim(), text)?);
+            continue;
+        }
+        if i.get(..8).is_some_and(|p| p.eq_ignore_ascii_case("unicode ")) {
+            let Some(c) = parse_code_point(i[8..].trim()) else {
+                return Err(format!("Bad code point in \"{i}\""));
+            };
+            exprs.push(Expr::Unicode(UnicodeExpr {
+                text: c.to_string(),
+            }));
+            continue;
+        }
+        let i = i.to_lowercase();
         let mut tmp_split: Vec<&str> = Vec::new();
         for sub_i in i.split(" ") {
             tmp_split.push(sub_i);
         }
+        // Motion and wheel are only pressed
+        let relative = |key: Key| {
+            Expr::Key(KeyExpr {
+                key,
+                action: Some(Action::Press),
+            })
+        };
         match tmp_split.len() {
             1 => {
                 exprs.push(Expr::Key(KeyExpr {
-                    key: to_u_key(tmp_split[0]).unwrap(),
+                    key: to_key(tmp_split[0])?,
                     action: None,
                 }));
             }
             2 => match tmp_split[0] {
                 "wait" => {
                     exprs.push(Expr::Wait(WaitExpr {
-                        milliseconds: tmp_split[1].parse::<u64>().unwrap(),
+                        milliseconds: tmp_split[1]
+                            .parse::<u64>()
+                            .map_err(|e| format!("Bad wait {}: {e}", tmp_split[1]))?,
+                    }));
+                }
+                "mouse_click" => {
+                    exprs.push(Expr::Key(KeyExpr {
+                        key: Key::Button(to_button(tmp_split[1])?),
+                        action: None,
                     }));
                 }
+                "scroll" => exprs.push(relative(to_scroll(tmp_split[1], "1")?)),
                 _ => {
                     exprs.push(Expr::Key(KeyExpr {
-                        key: to_u_key(tmp_split[0]).unwrap(),
-                        action: Some(Action::from_str(tmp_split[1]).unwrap()),
+                        key: to_key(tmp_split[0])?,
+                        action: Some(Action::from_str(tmp_split[1])?),
                     }));
                 }
             },
+            3 if tmp_split[0] == "scroll" => {
+                exprs.push(relative(to_scroll(tmp_split[1], tmp_split[2])?));
+            }
+            3 if tmp_split[0] == "mouse_move" => {
+                for (axis, amount) in [(Axis::X, tmp_split[1]), (Axis::Y, tmp_split[2])] {
+                    let amount = amount
+                        .parse::<i32>()
+                        .map_err(|e| format!("Bad mouse_move amount {amount}: {e}"))?;
+                    if amount != 0 {
+                        exprs.push(relative(Key::Move(axis, amount)));
+                    }
+                }
+            }
             _ => {
-                panic!("Unexpected number of elements in split: {:?}", tmp_split);
+                return Err(format!("Unexpected number of elements in split: {:?}", tmp_split));
             }
         }
     }
-    exprs
+    Ok(exprs)
+}
+
+/// Parses "<ms> <key> <down|up>", as used by the config self tests
+pub fn parse_timed_event(input: &str) -> Result<(i64, Event), String> {
+    let input = input.to_lowercase();
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err(format!("Expected \"<ms> <key> <down|up>\", got \"{input}\""));
+    }
+    let time = parts[0]
+        .parse::<i64>()
+        .map_err(|e| format!("Bad time {}: {e}", parts[0]))?;
+    Ok((
+        time,
+        Event {
+            key: to_key(parts[1])?,
+            action: Action::from_str(parts[2])?,
+        },
+    ))
+}
+
+/// Parses "leftmeta down, leftshift down, f23 down" into events with their
+/// offset in ms. Steps are split by `,` or `+`, a bare key is pressed and
+/// released, `wait <ms>` delays the steps after it.
+pub fn parse_key_sequence(input: &str) -> Result<Vec<(i64, Event)>, String> {
+    let input = input.to_lowercase();
+    let mut at = 0;
+    let mut events = Vec::new();
+    for step in input.split([',', '+']).map(str::trim) {
+        match step.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["wait", ms] => {
+                at += ms
+                    .parse::<i64>()
+                    .map_err(|e| format!("Bad wait {ms}: {e}"))?;
+            }
+            [key, action] => events.push((
+                at,
+                Event {
+                    key: to_key(key)?,
+                    action: Action::from_str(action)?,
+                },
+            )),
+            [key] => {
+                let key = to_key(key)?;
+                for action in [Action::Press, Action::Release] {
+                    events.push((at, Event { key, action }));
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Expected \"<key> [down|up]\" or \"wait <ms>\", got \"{step}\""
+                ));
+            }
+        }
+    }
+    Ok(events)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_parse_key_sequence() {
+        let press = |key: UKey| Event {
+            key: key.into(),
+            action: Action::Press,
+        };
+        let release = |key: UKey| Event {
+            key: key.into(),
+            action: Action::Release,
+        };
+        assert_eq!(
+            parse_key_sequence("leftmeta down, LeftShift down + wait 20, f23, leftshift up")
+                .unwrap(),
+            vec![
+                (0, press(UKey::LeftMeta)),
+                (0, press(UKey::LeftShift)),
+                (20, press(UKey::F23)),
+                (20, release(UKey::F23)),
+                (20, release(UKey::LeftShift)),
+            ]
+        );
+        assert!(parse_key_sequence("nokey down").is_err());
+        assert!(parse_key_sequence("a sideways").is_err());
+        assert!(parse_key_sequence("wait soon").is_err());
+        assert!(parse_key_sequence("a, , b").is_err());
+    }
+
     #[test]
     fn test_parser() {
         macro_rules! assert_parsed_exprs {
             ($input:expr, $expected:expr) => {
-                let exprs = parse_expr($input);
+                let exprs = parse_expr($input).unwrap();
                 assert_eq!(exprs, $expected);
             };
         }
@@ -175,17 +417,17 @@ mod tests {
             "leftctrl Down  + Wait 500 + leftctrl up + wait 200 +      esc",
             vec![
                 Expr::Key(KeyExpr {
-                    key: UKey::LeftControl,
+                    key: Key::Keyboard(UKey::LeftControl),
                     action: Some(Action::Press),
                 }),
                 Expr::Wait(WaitExpr { milliseconds: 500 }),
                 Expr::Key(KeyExpr {
-                    key: UKey::LeftControl,
+                    key: Key::Keyboard(UKey::LeftControl),
                     action: Some(Action::Release),
                 }),
                 Expr::Wait(WaitExpr { milliseconds: 200 }),
                 Expr::Key(KeyExpr {
-                    key: UKey::Esc,
+                    key: Key::Keyboard(UKey::Esc),
                     action: None,
                 }),
             ]
@@ -197,15 +439,15 @@ mod tests {
             vec![
                 Expr::Wait(WaitExpr { milliseconds: 1000 }),
                 Expr::Key(KeyExpr {
-                    key: UKey::A,
+                    key: Key::Keyboard(UKey::A),
                     action: Some(Action::Release),
                 }),
                 Expr::Key(KeyExpr {
-                    key: UKey::B,
+                    key: Key::Keyboard(UKey::B),
                     action: Some(Action::Press),
                 }),
                 Expr::Key(KeyExpr {
-                    key: UKey::C,
+                    key: Key::Keyboard(UKey::C),
                     action: None,
                 }),
             ]
@@ -214,7 +456,7 @@ mod tests {
         assert_parsed_exprs!(
             "esc",
             vec![Expr::Key(KeyExpr {
-                key: UKey::Esc,
+                key: Key::Keyboard(UKey::Esc),
                 action: None,
             })]
         );
@@ -222,7 +464,7 @@ mod tests {
         assert_parsed_exprs!(
             "leftshift down",
             vec![Expr::Key(KeyExpr {
-                key: UKey::LeftShift,
+                key: Key::Keyboard(UKey::LeftShift),
                 action: Some(Action::Press),
             })]
         );
@@ -237,9 +479,125 @@ mod tests {
         assert_parsed_exprs!("wait 50", vec![Expr::Wait(WaitExpr { milliseconds: 50 })]);
 
         let inp = "leftctrl Down + Wait 500 + leftctrl up + wait 200 +      esc";
-        let exprs = parse_expr(&inp);
+        let exprs = parse_expr(inp).unwrap();
         for e in exprs {
             println!("Expressions {e:?}");
         }
     }
+
+    #[test]
+    fn test_parse_type() {
+        assert_eq!(
+            parse_expr(r#"leftctrl down + type "Hello, World!" + Type "a + b \"c\"\n" + leftctrl up"#).unwrap(),
+            vec![
+                Expr::Key(KeyExpr {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Some(Action::Press),
+                }),
+                Expr::Type(TypeExpr {
+                    text: "Hello, World!".to_string()
+                }),
+                Expr::Type(TypeExpr {
+                    text: "a + b \"c\"\n".to_string()
+                }),
+                Expr::Key(KeyExpr {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Some(Action::Release),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_unicode() {
+        assert_eq!(
+            parse_expr(r#"unicode "→" + unicode U+00E9 + a"#).unwrap(),
+            vec![
+                Expr::Unicode(UnicodeExpr {
+                    text: "→".to_string()
+                }),
+                Expr::Unicode(UnicodeExpr {
+                    text: "é".to_string()
+                }),
+                Expr::Key(KeyExpr {
+                    key: Key::Keyboard(UKey::A),
+                    action: None,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_exec() {
+        assert_eq!(
+            parse_expr(r#"exec "notify-send 'Hi There'" + Exec_As Alice "echo \"$HOME\"""#).unwrap(),
+            vec![
+                Expr::Exec(ExecExpr {
+                    command: "notify-send 'Hi There'".to_string(),
+                    user: None,
+                }),
+                Expr::Exec(ExecExpr {
+                    command: "echo \"$HOME\"".to_string(),
+                    user: Some("Alice".to_string()),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mouse() {
+        let press = |key| {
+            Expr::Key(KeyExpr {
+                key,
+                action: Some(Action::Press),
+            })
+        };
+        assert_eq!(
+            parse_expr("mouse_click left + mouse_move 10 0 + mouse_move -3 4 + scroll down 3 + scroll right + btn_side up").unwrap(),
+            vec![
+                Expr::Key(KeyExpr {
+                    key: Key::Button(UButton::Left),
+                    action: None,
+                }),
+                press(Key::Move(Axis::X, 10)),
+                press(Key::Move(Axis::X, -3)),
+                press(Key::Move(Axis::Y, 4)),
+                press(Key::Scroll(Axis::Y, -3)),
+                press(Key::Scroll(Axis::X, 1)),
+                Expr::Key(KeyExpr {
+                    key: Key::Button(UButton::Side),
+                    action: Some(Action::Release),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_expr("nokey").is_err());
+        assert!(parse_expr("a sideways").is_err());
+        assert!(parse_expr("wait soon").is_err());
+        assert!(parse_expr("mouse_move 1 far").is_err());
+        assert!(parse_expr("a b c d").is_err());
+        assert!(parse_expr("unicode U+D800").is_err());
+        assert!(parse_expr(r#"type "open"#).is_err());
+        assert!(parse_expr(r#"shout "hi""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_timed_event() {
+        assert_eq!(
+            parse_timed_event("300  LeftCtrl up"),
+            Ok((
+                300,
+                Event {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Action::Release,
+                }
+            ))
+        );
+        assert!(parse_timed_event("leftctrl up").is_err());
+        assert!(parse_timed_event("10 leftctrl").is_err());
+        assert!(parse_timed_event("10 nokey up").is_err());
+    }
 }
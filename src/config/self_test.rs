@@ -0,0 +1,148 @@
+use serde::Deserialize;
+
+use super::parser::parse_timed_event;
+use super::{_parse_config, Config, ParsedConfig};
+use crate::clock::{Clock, VirtualClock};
+use crate::exec::ExecCommand;
+use crate::key_buffer::{Event, KeyBuffer};
+use crate::log_debug;
+use std::sync::Arc;
+
+// How long the simulation keeps going after the last input
+const SETTLE_MS: i64 = 10_000;
+
+/// `[[test]]` entry of the config file
+#[derive(Deserialize, Debug, Clone)]
+pub struct TestCase {
+    pub name: Option<String>,
+    pub input: Vec<String>,
+    pub output: Vec<String>,
+}
+
+pub type Timeline = Vec<(i64, Event)>;
+
+/// Feeds timed input events through a `KeyBuffer` on a virtual clock and
+/// returns everything that came out, with the time it came out at.
+pub fn simulate(config: ParsedConfig, input: &[(i64, Event)]) -> Timeline {
+    let clock = VirtualClock::new();
+    let buffer = KeyBuffer::with_clock(config, clock.clone()).unwrap();
+    // Commands are not run while simulating, KBD_LOG=debug shows them
+    buffer.set_exec_runner(Arc::new(|c: &ExecCommand| {
+        log_debug!("Skipped exec"; command = c.command, user = c.user)
+    }));
+    let mut input: Timeline = input.to_vec();
+    input.sort_by_key(|(t, _)| *t);
+    let end = input.last().map_or(0, |(t, _)| *t) + SETTLE_MS;
+
+    let mut output = Timeline::new();
+    let mut next_input = 0;
+    loop {
+        let due = [input.get(next_input).map(|(t, _)| *t), clock.next_due()]
+            .into_iter()
+            .flatten()
+            .min();
+        let now = match due {
+            Some(t) if t <= end => t,
+            _ => break,
+        };
+        clock.advance_to(now);
+        while let Some(event) = buffer.try_pop() {
+            output.push((now, event));
+        }
+        while let Some((t, event)) = input.get(next_input)
+            && *t <= now
+        {
+            buffer.push(event.key, event.action);
+            next_input += 1;
+        }
+        while let Some(event) = buffer.try_pop() {
+            output.push((clock.now_ms(), event));
+        }
+    }
+    output
+}
+
+fn parse_timeline(lines: &[String]) -> Result<Timeline, String> {
+    lines.iter().map(|l| parse_timed_event(l)).collect()
+}
+
+pub fn format_timeline(timeline: &Timeline) -> String {
+    timeline
+        .iter()
+        .map(|(t, e)| format!("    {t} {} {:?}", e.key, e.action))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs one case, returns a description of the mismatch if it fails
+pub fn run_case(config: &Config, case: &TestCase) -> Result<(), String> {
+    let input = parse_timeline(&case.input)?;
+    let expected = parse_timeline(&case.output)?;
+    let actual = simulate(_parse_config(config)?, &input);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "  expected:\n{}\n  got:\n{}",
+            format_timeline(&expected),
+            format_timeline(&actual)
+        ))
+    }
+}
+
+/// Runs every `[[test]]` of the config, returns whether all of them passed
+pub fn run_config_tests(config: &Config) -> bool {
+    let mut failed = 0;
+    for (idx, case) in config.test.iter().enumerate() {
+        let name = case.name.clone().unwrap_or(format!("test #{}", idx + 1));
+        match run_case(config, case) {
+            Ok(()) => println!("ok     {name}"),
+            Err(e) => {
+                failed += 1;
+                println!("FAILED {name}\n{e}");
+            }
+        }
+    }
+    println!("{} passed, {} failed", config.test.len() - failed, failed);
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_case() {
+        let config: Config = toml::from_str(
+            r#"
+            delay_ms = 50
+            [main]
+            "leftmeta + leftshift + F23" = "leftctrl down + wait 300 + leftctrl up"
+            "a" = "b"
+
+            [[test]]
+            name = "copilot"
+            input = ["0 leftmeta down", "0 leftshift down", "0 f23 down",
+                     "40 f23 up", "40 leftshift up", "45 leftmeta up"]
+            output = ["45 leftctrl down", "345 leftctrl up"]
+
+            [[test]]
+            input = ["0 a down", "3 a up", "10 x down", "20 x up"]
+            output = ["3 b down", "3 b up", "10 x down", "20 x up"]
+
+            [[test]]
+            input = ["0 a down", "60 a up"]
+            output = ["0 b down"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.test.len(), 3);
+        assert_eq!(run_case(&config, &config.test[0]), Ok(()));
+        assert_eq!(run_case(&config, &config.test[1]), Ok(()));
+        // "a" timed out on its own, both events pass through after the delay
+        let err = run_case(&config, &config.test[2]).unwrap_err();
+        assert!(err.contains("50 A Press"), "{err}");
+        assert!(err.contains("60 A Release"), "{err}");
+        assert!(!run_config_tests(&config));
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Deserialize;
+
+/// How the desktop is asked for a character that has no key
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeMethod {
+    /// GTK/IBus: ctrl+shift+u, the hex code point, space
+    #[default]
+    Ibus,
+    /// Compose key followed by two characters, see `compose_sequence`
+    Compose,
+}
+
+// Default X11 compose sequences for the characters people ask for most
+const COMPOSE: &[(char, &str)] = &[
+    ('á', "'a"),
+    ('é', "'e"),
+    ('í', "'i"),
+    ('ó', "'o"),
+    ('ú', "'u"),
+    ('Á', "'A"),
+    ('É', "'E"),
+    ('Í', "'I"),
+    ('Ó', "'O"),
+    ('Ú', "'U"),
+    ('à', "`a"),
+    ('è', "`e"),
+    ('ì', "`i"),
+    ('ò', "`o"),
+    ('ù', "`u"),
+    ('â', "^a"),
+    ('ê', "^e"),
+    ('î', "^i"),
+    ('ô', "^o"),
+    ('û', "^u"),
+    ('ä', "\"a"),
+    ('ë', "\"e"),
+    ('ï', "\"i"),
+    ('ö', "\"o"),
+    ('ü', "\"u"),
+    ('Ä', "\"A"),
+    ('Ö', "\"O"),
+    ('Ü', "\"U"),
+    ('ñ', "~n"),
+    ('Ñ', "~N"),
+    ('ã', "~a"),
+    ('õ', "~o"),
+    ('ç', ",c"),
+    ('Ç', ",C"),
+    ('ß', "ss"),
+    ('€', "=e"),
+    ('£', "-L"),
+    ('©', "oc"),
+    ('°', "oo"),
+    ('±', "+-"),
+    ('×', "xx"),
+    ('÷', ":-"),
+    ('½', "12"),
+    ('→', "->"),
+    ('←', "<-"),
+    ('≤', "<="),
+    ('≥', ">="),
+    ('≠', "/="),
+];
+
+/// Characters typed after the compose key to get `c`
+pub fn compose_sequence(c: char) -> Option<&'static str> {
+    COMPOSE.iter().find(|(ch, _)| *ch == c).map(|(_, seq)| *seq)
+}
+
+/// Parses `U+00E9` into its character
+pub fn parse_code_point(s: &str) -> Option<char> {
+    let hex = s.strip_prefix("U+").or_else(|| s.strip_prefix("u+"))?;
+    char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unicode_helpers() {
+        assert_eq!(parse_code_point("U+00E9"), Some('é'));
+        assert_eq!(parse_code_point("u+2192"), Some('→'));
+        assert_eq!(parse_code_point("U+D800"), None);
+        assert_eq!(parse_code_point("00E9"), None);
+        assert_eq!(compose_sequence('é'), Some("'e"));
+        assert_eq!(compose_sequence('∀'), None);
+    }
+}
@@ -0,0 +1,426 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::{parse_key_sequence, try_load_config};
+use crate::key_buffer::KeyBuffer;
+use crate::log::{filter, set_filter};
+use crate::{log_error, log_info, log_warn};
+
+#[cfg(debug_assertions)]
+pub const SOCKET_PATH: &str = "/tmp/kbd.sock";
+#[cfg(not(debug_assertions))]
+pub const SOCKET_PATH: &str = "/run/kbd.sock";
+
+// How often the socket threads look for a shutdown
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+const HELP: &str = "commands: status, reload, pause [duration], resume, \
+    layer <name> on|off|toggle, inject <keys>, log [filter], list-bindings, list-layers, \
+    list-devices, metrics";
+
+fn group_id(name: &str) -> Result<u32, Box<dyn Error>> {
+    let c_name = std::ffi::CString::new(name)?;
+    // getgrnam isn't reentrant, the socket is set up once at start
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(format!("Unknown group {name}").into());
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+/// "500ms", "30s", "5m", "1h", a bare number is seconds
+fn parse_duration_ms(s: &str) -> Result<i64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: i64 = amount.parse().map_err(|_| format!("Bad duration {s}"))?;
+    let scale = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        _ => return Err(format!("Bad duration {s}")),
+    };
+    Ok(amount * scale)
+}
+
+/// Running daemon as seen from the control socket.
+///
+/// One command per line. The reply is zero or more lines of data followed
+/// by a line with `ok`, or with `error <message>` when the command failed.
+pub struct Control {
+    buffer: Arc<KeyBuffer>,
+    config_path: String,
+    // Grabbed input devices, "<path> <name>"
+    devices: Vec<String>,
+    started: Instant,
+}
+
+impl Control {
+    pub fn new(buffer: Arc<KeyBuffer>, config_path: &str, devices: Vec<String>) -> Self {
+        Control {
+            buffer,
+            config_path: config_path.to_string(),
+            devices,
+            started: Instant::now(),
+        }
+    }
+
+    /// Listens on `path` in a thread of its own, one more per client.
+    /// Members of `group` may connect too. The thread removes the socket
+    /// and ends, along with its clients, once `stop` is set.
+    pub fn serve(
+        self,
+        path: &str,
+        group: Option<&str>,
+        stop: Arc<AtomicBool>,
+    ) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        // Left over from a daemon that didn't shut down cleanly
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        // Anyone on the socket can drive the keyboard, root only by default
+        let mode = match group {
+            Some(group) => {
+                std::os::unix::fs::chown(path, None, Some(group_id(group)?))?;
+                0o660
+            }
+            None => 0o600,
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        listener.set_nonblocking(true)?;
+        let control = Arc::new(self);
+        let path = path.to_string();
+        Ok(thread::spawn(move || {
+            let mut clients = Vec::new();
+            while !stop.load(Ordering::SeqCst) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(STOP_POLL);
+                        continue;
+                    }
+                    Err(e) => {
+                        log_error!("Control socket failed: {e}");
+                        break;
+                    }
+                };
+                clients.retain(|client: &JoinHandle<()>| !client.is_finished());
+                let control = control.clone();
+                let stop = stop.clone();
+                clients.push(thread::spawn(move || {
+                    if let Err(e) = control.handle_client(stream, &stop) {
+                        log_warn!("Control client failed: {e}");
+                    }
+                }));
+            }
+            for client in clients {
+                let _ = client.join();
+            }
+            let _ = std::fs::remove_file(path);
+        }))
+    }
+
+    fn handle_client(&self, stream: UnixStream, stop: &AtomicBool) -> Result<(), Box<dyn Error>> {
+        stream.set_nonblocking(false)?;
+        // Wakes up now and then to see whether kbd is stopping
+        stream.set_read_timeout(Some(STOP_POLL))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while !stop.load(Ordering::SeqCst) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let command = std::mem::take(&mut line);
+            let command = command.trim_end_matches(['\r', '\n']);
+            if command.trim().is_empty() {
+                continue;
+            }
+            let reply = match self.execute(command) {
+                Ok(lines) => lines.into_iter().chain(["ok".to_string()]).collect(),
+                Err(e) => vec![format!("error {}", e.replace('\n', " "))],
+            };
+            writeln!(writer, "{}", reply.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    /// Feeds the keys to the buffer as if typed, waits included, so they go
+    /// through the bindings like real ones
+    fn inject(&self, keys: &str) -> Result<Vec<String>, String> {
+        let events = parse_key_sequence(keys)?;
+        let start = Instant::now();
+        for (at, event) in &events {
+            let due = Duration::from_millis(*at as u64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            self.buffer.push(event.key, event.action);
+        }
+        Ok(vec![format!("injected {}", events.len())])
+    }
+
+    /// Runs one command, returns the data lines of the reply
+    pub fn execute(&self, line: &str) -> Result<Vec<String>, String> {
+        if let Some(keys) = line.strip_prefix("inject ") {
+            return self.inject(keys);
+        }
+        if let Some(spec) = line.strip_prefix("log ") {
+            set_filter(spec)?;
+            return Ok(vec![format!("log {}", filter())]);
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["status"] => {
+                let config = self.buffer.config();
+                let mut layers: Vec<String> = self.buffer.layers().into_iter().collect();
+                layers.sort();
+                Ok(vec![
+                    format!(
+                        "state {}",
+                        if self.buffer.is_paused() {
+                            "paused"
+                        } else {
+                            "running"
+                        }
+                    ),
+                    format!("uptime_s {}", self.started.elapsed().as_secs()),
+                    format!("bindings {}", config.bindings().count()),
+                    format!("layers {}", layers.join(" ")),
+                    format!("devices {}", self.devices.len()),
+                    format!("scheduled {}", self.buffer.scheduled()),
+                ])
+            }
+            ["reload"] => {
+                let config = try_load_config(&self.config_path)?;
+                let count = config.bindings().count();
+                if let Some(spec) = &config.log {
+                    set_filter(spec)?;
+                }
+                self.buffer.reload(config);
+                log_info!("Reloaded"; config = self.config_path, bindings = count);
+                Ok(vec![format!("bindings {count}")])
+            }
+            ["log"] => Ok(vec![format!("log {}", filter())]),
+            ["pause"] => {
+                self.buffer.pause(None);
+                Ok(Vec::new())
+            }
+            ["pause", duration] => {
+                self.buffer.pause(Some(parse_duration_ms(duration)?));
+                Ok(Vec::new())
+            }
+            ["resume"] => {
+                self.buffer.resume();
+                Ok(Vec::new())
+            }
+            ["layer", name, state] => {
+                let on = match *state {
+                    "on" => true,
+                    "off" => false,
+                    "toggle" => !self.buffer.layers().contains(*name),
+                    _ => return Err(format!("Expected on, off or toggle, got {state}")),
+                };
+                if !self.buffer.set_layer(name, on) {
+                    return Err(format!("No layer {name}"));
+                }
+                Ok(vec![format!(
+                    "layer {name} {}",
+                    if on { "on" } else { "off" }
+                )])
+            }
+            ["list-bindings"] => Ok(self
+                .buffer
+                .config()
+                .bindings()
+                .map(|(layer, trigger, action)| match layer {
+                    Some(layer) => format!("[{layer}] {trigger} = {action}"),
+                    None => format!("{trigger} = {action}"),
+                })
+                .collect()),
+            ["list-layers"] => {
+                let config = self.buffer.config();
+                let active = self.buffer.layers();
+                let mut names: Vec<&str> = config.bindings().filter_map(|(l, _, _)| l).collect();
+                names.dedup();
+                Ok(names
+                    .into_iter()
+                    .map(|name| {
+                        let state = if active.contains(name) { "on" } else { "off" };
+                        format!("{name} {state}")
+                    })
+                    .collect())
+            }
+            ["list-devices"] => Ok(self.devices.clone()),
+            ["metrics"] => Ok(self
+                .buffer
+                .metrics()
+                .render()
+                .lines()
+                .map(str::to_string)
+                .collect()),
+            ["help"] => Ok(vec![HELP.to_string()]),
+            _ => Err(format!("Unknown command \"{line}\", {HELP}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::config::config_from_str;
+    use crate::key_buffer::{Action, UKey};
+
+    fn control(config_path: &str) -> (Control, Arc<VirtualClock>) {
+        let config = config_from_str(
+            r#"
+            delay_ms = 1000
+            [main]
+            "a" = "b"
+            "#,
+        );
+        let clock = VirtualClock::new();
+        let buffer = KeyBuffer::with_clock(config, clock.clone()).unwrap();
+        let devices = vec!["/dev/input/event3 kbd".to_string()];
+        (Control::new(buffer, config_path, devices), clock)
+    }
+
+    #[test]
+    fn test_parse_duration_ms() {
+        assert_eq!(parse_duration_ms("250ms"), Ok(250));
+        assert_eq!(parse_duration_ms("30s"), Ok(30_000));
+        assert_eq!(parse_duration_ms("30"), Ok(30_000));
+        assert_eq!(parse_duration_ms("5m"), Ok(300_000));
+        assert!(parse_duration_ms("s").is_err());
+        assert!(parse_duration_ms("-5s").is_err());
+    }
+
+    #[test]
+    fn test_control_commands() {
+        let (control, clock) = control("/nonexistent/config.toml");
+        let status = control.execute("status").unwrap();
+        assert_eq!(status[0], "state running");
+        assert!(status.contains(&"bindings 1".to_string()));
+        assert_eq!(control.execute("list-bindings").unwrap(), vec!["a = b"]);
+        assert_eq!(
+            control.execute("list-devices").unwrap(),
+            vec!["/dev/input/event3 kbd"]
+        );
+
+        // Buffered keys are let out, then everything passes untouched
+        control.buffer.push(UKey::A, Action::Press);
+        control.execute("pause").unwrap();
+        assert_eq!(control.execute("status").unwrap()[0], "state paused");
+        control.buffer.push(UKey::A, Action::Release);
+        let keys: Vec<_> = std::iter::from_fn(|| control.buffer.try_pop())
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(keys, vec![UKey::A.into(), UKey::A.into()]);
+        control.execute("resume").unwrap();
+        assert_eq!(control.execute("status").unwrap()[0], "state running");
+
+        // Injected keys go through the bindings, the macro runs on the clock
+        assert_eq!(
+            control.execute("inject a, x down, x up").unwrap(),
+            vec!["injected 4"]
+        );
+        clock.advance(10);
+        let keys: Vec<_> = std::iter::from_fn(|| control.buffer.try_pop())
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                UKey::X.into(),
+                UKey::X.into(),
+                UKey::B.into(),
+                UKey::B.into()
+            ]
+        );
+        assert!(control.execute("inject a sideways").is_err());
+
+        assert!(control.execute("pause 2m").is_ok());
+        assert!(control.execute("pause 2x").is_err());
+        assert!(control.execute("layer nav on").is_err());
+        assert!(control.execute("reload").is_err());
+        assert!(control.execute("frobnicate").is_err());
+
+        assert!(control.execute("log loud").is_err());
+        assert_eq!(
+            control.execute("log warn,exec=debug").unwrap(),
+            vec!["log warn,exec=debug"]
+        );
+        assert_eq!(control.execute("log").unwrap(), vec!["log warn,exec=debug"]);
+        control
+            .execute(&format!("log {}", crate::log::DEFAULT_FILTER))
+            .unwrap();
+
+        // Only events that went through an output thread are counted
+        let metrics = control.execute("metrics").unwrap();
+        assert!(metrics[0].starts_with("# HELP kbd_latency_seconds"));
+        assert!(metrics.contains(&r#"kbd_latency_seconds_count{path="remapped"} 0"#.to_string()));
+    }
+
+    #[test]
+    fn test_control_reload_and_socket() {
+        let dir = std::env::temp_dir().join(format!("kbd-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, "[main]\n\"a\" = \"b\"\n\"c\" = \"d\"\n").unwrap();
+        let socket = dir.join("kbd.sock");
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = control(config_path.to_str().unwrap())
+            .0
+            .serve(socket.to_str().unwrap(), None, stop.clone())
+            .unwrap();
+
+        let stream = UnixStream::connect(&socket).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines().map(|l| l.unwrap());
+        writeln!(writer, "reload").unwrap();
+        assert_eq!(lines.next().unwrap(), "bindings 2");
+        assert_eq!(lines.next().unwrap(), "ok");
+        writeln!(writer, "list-bindings").unwrap();
+        assert_eq!(lines.next().unwrap(), "a = b");
+        assert_eq!(lines.next().unwrap(), "c = d");
+        assert_eq!(lines.next().unwrap(), "ok");
+
+        std::fs::write(
+            &config_path,
+            "[main]\n\"a\" = \"b\"\n[layers.nav]\n\"h\" = \"left\"\n",
+        )
+        .unwrap();
+        writeln!(writer, "reload\nlayer nav toggle\nlist-layers").unwrap();
+        let replies: Vec<String> = lines.by_ref().take(6).collect();
+        assert_eq!(
+            replies,
+            vec!["bindings 2", "ok", "layer nav on", "ok", "nav on", "ok"]
+        );
+
+        std::fs::write(&config_path, "[main]\n\"nokey\" = \"b\"\n").unwrap();
+        writeln!(writer, "reload").unwrap();
+        let reply = lines.next().unwrap();
+        assert!(reply.starts_with("error ") && reply.contains("Unknown key nokey"));
+        writeln!(writer, "status").unwrap();
+        assert!(lines.any(|l| l == "layers nav"));
+
+        // The connected client doesn't keep it from stopping
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+        assert!(!socket.exists());
+        // Rest of the status, then the connection is closed
+        assert_eq!(lines.last().unwrap(), "ok");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
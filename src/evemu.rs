@@ -0,0 +1,284 @@
+use evdev::{AttributeSetRef, EventType, InputEvent, InputId, Key};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::Timeline;
+use crate::key_grabber::{InputSource, to_key_event};
+
+const EV_CNT: usize = 0x20;
+const KEY_CNT: usize = 0x300;
+// How often a replay waiting for its next event looks at its stop flag
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+/// Writes events in the evemu text format, readable by `evemu-play` and
+/// `kbd replay`
+pub struct EvemuWriter<W: Write> {
+    out: W,
+    start: Option<SystemTime>,
+}
+
+fn write_bits(out: &mut impl Write, kind: u16, codes: &[u16], count: usize) -> io::Result<()> {
+    let mut mask = vec![0u8; count / 8];
+    for code in codes {
+        mask[*code as usize / 8] |= 1 << (code % 8);
+    }
+    for chunk in mask.chunks(8) {
+        write!(out, "B: {kind:02x}")?;
+        for byte in chunk {
+            write!(out, " {byte:02x}")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+impl<W: Write> EvemuWriter<W> {
+    pub fn new(
+        mut out: W,
+        name: &str,
+        id: InputId,
+        keys: Option<&AttributeSetRef<Key>>,
+    ) -> io::Result<Self> {
+        writeln!(out, "# EVEMU 1.3")?;
+        writeln!(out, "# Recorded by kbd")?;
+        writeln!(out, "N: {name}")?;
+        writeln!(
+            out,
+            "I: {:04x} {:04x} {:04x} {:04x}",
+            id.bus_type().0,
+            id.vendor(),
+            id.product(),
+            id.version()
+        )?;
+        let keys: Vec<u16> = keys.map_or(Vec::new(), |k| k.iter().map(|k| k.code()).collect());
+        write_bits(
+            &mut out,
+            0,
+            &[EventType::SYNCHRONIZATION.0, EventType::KEY.0],
+            EV_CNT,
+        )?;
+        write_bits(&mut out, EventType::KEY.0, &keys, KEY_CNT)?;
+        out.flush()?;
+        Ok(EvemuWriter { out, start: None })
+    }
+
+    /// Writes one event, time is relative to the first written one
+    pub fn write_event(&mut self, event: &InputEvent) -> io::Result<()> {
+        let start = *self.start.get_or_insert(event.timestamp());
+        let time = event
+            .timestamp()
+            .duration_since(start)
+            .unwrap_or(Duration::ZERO);
+        writeln!(
+            self.out,
+            "E: {}.{:06} {:04x} {:04x} {}",
+            time.as_secs(),
+            time.subsec_micros(),
+            event.event_type().0,
+            event.code(),
+            event.value()
+        )?;
+        // Keep the file usable if recording is interrupted
+        if event.event_type() == EventType::SYNCHRONIZATION {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub type Recording = Vec<(Duration, InputEvent)>;
+
+/// Reads the `E:` lines of an evemu recording, everything else is skipped
+pub fn parse(text: &str) -> Result<Recording, String> {
+    let mut events = Recording::new();
+    for (idx, line) in text.lines().enumerate() {
+        let Some(line) = line.strip_prefix("E:") else {
+            continue;
+        };
+        let line = line.split('#').next().unwrap_or_default();
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let bad = |what: &str| format!("Line {}: bad {what} in \"{line}\"", idx + 1);
+        if parts.len() != 4 {
+            return Err(bad("event"));
+        }
+        let (secs, micros) = parts[0].split_once('.').unwrap_or((parts[0], "0"));
+        let secs = secs.parse::<u64>().map_err(|_| bad("time"))?;
+        let micros = micros
+            .chars()
+            .chain(std::iter::repeat('0'))
+            .take(6)
+            .collect::<String>()
+            .parse::<u32>()
+            .map_err(|_| bad("time"))?;
+        let kind = u16::from_str_radix(parts[1], 16).map_err(|_| bad("type"))?;
+        let code = u16::from_str_radix(parts[2], 16).map_err(|_| bad("code"))?;
+        let value = parts[3].parse::<i32>().map_err(|_| bad("value"))?;
+        events.push((
+            Duration::new(secs, micros * 1000),
+            InputEvent::new(EventType(kind), code, value),
+        ));
+    }
+    Ok(events)
+}
+
+/// Key events of a recording, in milliseconds from its start
+pub fn key_timeline(recording: &Recording) -> Timeline {
+    recording
+        .iter()
+        .filter_map(|(time, e)| to_key_event(e).map(|k| (time.as_millis() as i64, k)))
+        .collect()
+}
+
+/// Plays a recording back in real time
+pub struct EvemuSource {
+    events: VecDeque<(Duration, InputEvent)>,
+    start: Option<Instant>,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl EvemuSource {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let recording = parse(&std::fs::read_to_string(path)?)?;
+        Ok(EvemuSource {
+            events: recording.into(),
+            start: None,
+            stop: None,
+        })
+    }
+}
+
+impl InputSource for EvemuSource {
+    fn next_event(&mut self) -> Result<Option<InputEvent>, Box<dyn Error>> {
+        let Some((time, event)) = self.events.pop_front() else {
+            return Ok(None);
+        };
+        let start = *self.start.get_or_insert_with(Instant::now);
+        while let Some(wait) = (start + time).checked_duration_since(Instant::now()) {
+            if self.stop.as_ref().is_some_and(|s| s.load(Ordering::SeqCst)) {
+                return Ok(None);
+            }
+            thread::sleep(wait.min(STOP_POLL));
+        }
+        Ok(Some(event))
+    }
+
+    fn stop_on(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn label(&self) -> &str {
+        "replay"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_buffer::{Action, Event, UKey};
+    use evdev::{AttributeSet, BusType};
+
+    #[test]
+    fn test_evemu_roundtrip() {
+        let mut keys = AttributeSet::<Key>::new();
+        keys.insert(Key::KEY_A);
+        keys.insert(Key::KEY_F23);
+        let mut out = Vec::<u8>::new();
+        {
+            let mut writer = EvemuWriter::new(
+                &mut out,
+                "AT Translated Set 2 keyboard",
+                InputId::new(BusType::BUS_I8042, 1, 1, 0xab41),
+                Some(&keys),
+            )
+            .unwrap();
+            let start = SystemTime::now();
+            for (ms, kind, code, value) in [
+                (0, EventType::KEY, Key::KEY_A.code(), 1),
+                (0, EventType::SYNCHRONIZATION, 0, 0),
+                (120, EventType::KEY, Key::KEY_A.code(), 0),
+                (120, EventType::SYNCHRONIZATION, 0, 0),
+            ] {
+                let event = event_at(
+                    InputEvent::new(kind, code, value),
+                    start + Duration::from_millis(ms),
+                );
+                writer.write_event(&event).unwrap();
+            }
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# EVEMU 1.3\n"));
+        assert!(text.contains("N: AT Translated Set 2 keyboard\n"));
+        assert!(text.contains("I: 0011 0001 0001 ab41\n"));
+        assert!(text.contains("B: 00 03 00 00 00\n"));
+        assert!(text.contains("E: 0.120000 0001 001e 0\n"));
+
+        let recording = parse(&text).unwrap();
+        assert_eq!(recording.len(), 4);
+        assert_eq!(recording[2].0, Duration::from_millis(120));
+        assert_eq!(
+            key_timeline(&recording),
+            vec![
+                (
+                    0,
+                    Event {
+                        key: UKey::A.into(),
+                        action: Action::Press
+                    }
+                ),
+                (
+                    120,
+                    Event {
+                        key: UKey::A.into(),
+                        action: Action::Release
+                    }
+                ),
+            ]
+        );
+    }
+
+    fn event_at(event: InputEvent, time: SystemTime) -> InputEvent {
+        let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let mut raw = *event.as_ref();
+        raw.time.tv_sec = since.as_secs() as _;
+        raw.time.tv_usec = since.subsec_micros() as _;
+        raw.into()
+    }
+
+    #[test]
+    fn test_evemu_parse() {
+        let recording = parse(
+            "# EVEMU 1.3\n\
+             N: kbd\n\
+             E: 0.000001 0004 0004 458792\t# EV_MSC / MSC_SCAN 458792\n\
+             E: 0.000001 0001 001d 1\t# EV_KEY / KEY_LEFTCTRL 1\n\
+             E: 1.500000 0001 001d 0\n",
+        )
+        .unwrap();
+        assert_eq!(recording.len(), 3);
+        assert_eq!(
+            key_timeline(&recording),
+            vec![
+                (
+                    0,
+                    Event {
+                        key: UKey::LeftControl.into(),
+                        action: Action::Press
+                    }
+                ),
+                (
+                    1500,
+                    Event {
+                        key: UKey::LeftControl.into(),
+                        action: Action::Release
+                    }
+                ),
+            ]
+        );
+        assert!(parse("E: 0.1 0001 zz 1").is_err());
+    }
+}
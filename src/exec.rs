@@ -0,0 +1,169 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::log_error;
+
+/// Shell command of an `exec` action, with the user and environment it runs
+/// with filled in from the config. There is always a user, nothing runs as
+/// root unless the config says so.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExecCommand {
+    pub command: String,
+    pub user: String,
+    pub env: Vec<(String, String)>,
+}
+
+struct Account {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+    shell: String,
+}
+
+/// Looks the user up in /etc/passwd
+fn find_account(passwd: &str, user: &str) -> Option<Account> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 7 || fields[0] != user {
+            return None;
+        }
+        Some(Account {
+            name: fields[0].to_string(),
+            uid: fields[2].parse().ok()?,
+            gid: fields[3].parse().ok()?,
+            home: fields[5].to_string(),
+            shell: fields[6].to_string(),
+        })
+    })
+}
+
+/// Supplementary groups of the user, `gid` included
+fn group_list(name: &CString, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, Box<dyn Error>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // Too small, `count` says how many there are
+        if count as usize <= groups.len() {
+            return Err(format!("No group list for {}", name.to_string_lossy()).into());
+        }
+        groups.resize(count as usize, 0);
+    }
+}
+
+/// Starts the command through `sh -c` and returns without waiting for it.
+/// The child drops to the uid, gid and groups of the user.
+pub fn spawn(exec: &ExecCommand) -> Result<(), Box<dyn Error>> {
+    let user = &exec.user;
+    let passwd = std::fs::read_to_string("/etc/passwd")?;
+    let account = find_account(&passwd, user).ok_or_else(|| format!("Unknown user {user}"))?;
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(&exec.command)
+        .stdin(Stdio::null())
+        .env_clear()
+        .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+        .env("HOME", &account.home)
+        .env("USER", &account.name)
+        .env("LOGNAME", &account.name)
+        .env("SHELL", &account.shell)
+        .env("XDG_RUNTIME_DIR", format!("/run/user/{}", account.uid))
+        .current_dir(&account.home);
+    // Already that user, nothing to drop
+    if account.uid != unsafe { libc::geteuid() } {
+        let name = CString::new(account.name.clone())?;
+        let (uid, gid) = (account.uid as libc::uid_t, account.gid as libc::gid_t);
+        // Looked up here, the child may only make async-signal-safe calls
+        let groups = group_list(&name, gid)?;
+        // Otherwise the child keeps root's supplementary groups. Std would
+        // switch uid before running this, after which groups can't be set.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    command.envs(exec.env.iter().map(|(k, v)| (k, v)));
+    let mut child = command.spawn()?;
+    // Reap it so no zombies pile up
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// `spawn` for callers that can't handle the error
+pub fn spawn_logged(exec: &ExecCommand) {
+    if let Err(e) = spawn(exec) {
+        log_error!("Failed to run command: {e}"; command = exec.command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_account() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      alice:x:1000:1000:Alice,,,:/home/alice:/usr/bin/zsh\n\
+                      broken:x:zz:1:::\n";
+        let alice = find_account(passwd, "alice").unwrap();
+        assert_eq!((alice.uid, alice.gid), (1000, 1000));
+        assert_eq!(alice.home, "/home/alice");
+        assert_eq!(alice.shell, "/usr/bin/zsh");
+        assert!(find_account(passwd, "bob").is_none());
+        assert!(find_account(passwd, "broken").is_none());
+    }
+
+    #[test]
+    fn test_group_list() {
+        let name = CString::new("root").unwrap();
+        let groups = group_list(&name, 4242).unwrap();
+        assert!(groups.contains(&4242));
+    }
+
+    #[test]
+    fn test_spawn() {
+        let dir = std::env::temp_dir().join(format!("kbd-exec-{}", std::process::id()));
+        // Whoever runs the tests, so no privileges are needed
+        let uid = unsafe { libc::getuid() }.to_string();
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap();
+        let user = passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() >= 7 && fields[2] == uid)
+            .map(|fields| fields[0].to_string())
+            .unwrap();
+        let exec = ExecCommand {
+            command: format!("echo \"$GREETING\" > {}", dir.display()),
+            user,
+            env: vec![("GREETING".to_string(), "hi".to_string())],
+        };
+        spawn(&exec).unwrap();
+        let mut text = String::new();
+        for _ in 0..100 {
+            text = std::fs::read_to_string(&dir).unwrap_or_default();
+            if !text.is_empty() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let _ = std::fs::remove_file(&dir);
+        assert_eq!(text, "hi\n");
+    }
+}
@@ -1,22 +1,28 @@
 #![allow(dead_code)]
-use crate::config::{ParsedConfig, action_to_events, get_action};
-use crate::debug_println;
-use crate::key_scheduler::KeyScheduler;
-use std::collections::VecDeque;
+use crate::clock::{ClockGuard, SafeClock, SystemClock};
+use crate::config::{Layers, ParsedConfig, Retrigger, ShutdownPolicy, action_to_outputs};
+use crate::{log_debug, log_error, log_trace};
+use crate::key_scheduler::{ExecRunner, KeyScheduler, MacroId};
+use crate::metrics::{Metrics, Path, Stamp};
+use crate::mouse_keys::MouseKeys;
+use crate::udev_loop::ALoop;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
 use std::hash::{Hash, Hasher};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use timer::Guard;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+pub use uinput::event::controller::Mouse as UButton;
 pub use uinput::event::keyboard::Key as UKey;
 
 extern crate chrono;
-extern crate timer;
 
 const DEFAULT_DELAY_MS: u64 = 3;
 const KEY_CAPASITY: usize = 10;
+// How often a waiting `pop` looks for a shutdown
+const POP_POLL: Duration = Duration::from_millis(50);
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash, Copy)]
 pub enum Action {
@@ -24,9 +30,55 @@ pub enum Action {
     Release,
 }
 
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Copy)]
+pub enum Axis {
+    X,
+    Y,
+}
+
+/// Anything that can be pressed, a key or a mouse button. Pointer motion
+/// and wheel clicks carry their amount and are only ever pressed.
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Copy)]
+pub enum Key {
+    Keyboard(UKey),
+    Button(UButton),
+    Move(Axis, i32),
+    Scroll(Axis, i32),
+}
+
+impl Key {
+    /// Motion and wheel, there is nothing to release for them
+    pub fn is_relative(&self) -> bool {
+        matches!(self, Key::Move(..) | Key::Scroll(..))
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Keyboard(key) => write!(f, "{key:?}"),
+            Key::Button(button) => write!(f, "Mouse{button:?}"),
+            Key::Move(axis, amount) => write!(f, "Move{axis:?} {amount}"),
+            Key::Scroll(axis, amount) => write!(f, "Scroll{axis:?} {amount}"),
+        }
+    }
+}
+
+impl From<UKey> for Key {
+    fn from(key: UKey) -> Self {
+        Key::Keyboard(key)
+    }
+}
+
+impl From<UButton> for Key {
+    fn from(button: UButton) -> Self {
+        Key::Button(button)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Event {
-    pub key: UKey,
+    pub key: Key,
     pub action: Action,
 }
 
@@ -40,7 +92,8 @@ impl Event {
 
 pub struct BufferEvent {
     pub event: Event,
-    pub guard: Option<Guard>,
+    pub guard: Option<ClockGuard>,
+    pub read_at: Instant,
 }
 
 impl std::fmt::Debug for BufferEvent {
@@ -61,152 +114,640 @@ impl BufferEvent {
     }
 }
 
-type SafeReceiver = Arc<Mutex<mpsc::Receiver<Event>>>;
-pub type SafeSender = Arc<Mutex<mpsc::Sender<Event>>>;
+/// Event on its way to the output, stamped when it stands for an input
+pub type Stamped = (Event, Option<Stamp>);
+
+/// What the output gets from the buffer
+#[derive(Debug)]
+pub enum Popped {
+    /// Sent on its own
+    Event(Stamped),
+    /// Part of the input frame being handled, synced with its end
+    Framed(Stamped),
+    /// The input frame is complete, as the source's SYN_REPORT says
+    FrameEnd,
+}
+
+/// Where events for the output are sent, stamps are dropped unless kept
+pub trait EventSender: Send {
+    fn send(&self, event: Event) -> Result<(), mpsc::SendError<Event>>;
+    fn send_stamped(&self, event: Event, _stamp: Stamp) -> Result<(), mpsc::SendError<Event>> {
+        self.send(event)
+    }
+    /// `send_stamped` for an input passed on while its frame is handled
+    fn send_framed(&self, event: Event, stamp: Stamp) -> Result<(), mpsc::SendError<Event>> {
+        self.send_stamped(event, stamp)
+    }
+    fn end_frame(&self) {}
+}
+
+impl EventSender for mpsc::Sender<Event> {
+    fn send(&self, event: Event) -> Result<(), mpsc::SendError<Event>> {
+        mpsc::Sender::send(self, event)
+    }
+}
+
+impl EventSender for mpsc::Sender<Popped> {
+    fn send(&self, event: Event) -> Result<(), mpsc::SendError<Event>> {
+        mpsc::Sender::send(self, Popped::Event((event, None))).map_err(unsent)
+    }
+
+    fn send_stamped(&self, event: Event, stamp: Stamp) -> Result<(), mpsc::SendError<Event>> {
+        mpsc::Sender::send(self, Popped::Event((event, Some(stamp)))).map_err(unsent)
+    }
+
+    fn send_framed(&self, event: Event, stamp: Stamp) -> Result<(), mpsc::SendError<Event>> {
+        mpsc::Sender::send(self, Popped::Framed((event, Some(stamp)))).map_err(unsent)
+    }
+
+    fn end_frame(&self) {
+        // Only fails with the buffer gone, and the output with it
+        let _ = mpsc::Sender::send(self, Popped::FrameEnd);
+    }
+}
+
+fn unsent(e: mpsc::SendError<Popped>) -> mpsc::SendError<Event> {
+    match e.0 {
+        Popped::Event((event, _)) | Popped::Framed((event, _)) => mpsc::SendError(event),
+        Popped::FrameEnd => unreachable!("frame ends are not events"),
+    }
+}
+
+fn passthrough(read_at: Instant) -> Stamp {
+    Stamp {
+        read_at,
+        path: Path::Passthrough,
+    }
+}
+
+type SafeReceiver = Arc<Mutex<mpsc::Receiver<Popped>>>;
+pub type SafeSender = Arc<Mutex<dyn EventSender>>;
 pub type KeyDeque = VecDeque<BufferEvent>;
+// Macros started by each binding, by index in the current config
+type Running = HashMap<usize, Vec<MacroId>>;
 
 pub struct KeyBuffer {
     deque: Arc<Mutex<KeyDeque>>,
-    push_channel: SafeSender,
-    _push_channel_r: SafeReceiver,
 
     pop_channel: SafeReceiver,
     _pop_channel_s: SafeSender,
-    timer: timer::Timer,
+    clock: SafeClock,
     key_scheduler: Arc<Mutex<KeyScheduler>>,
-    config: ParsedConfig,
+    // Finished macros are pruned on next use
+    running: Mutex<Running>,
+    mouse_keys: RwLock<Option<MouseKeys>>,
+    // Swapped as a whole on reload
+    config: RwLock<Arc<ParsedConfig>>,
+    layers: RwLock<Layers>,
+    paused: Arc<AtomicBool>,
+    // Ends a pause with a timeout
+    resume_guard: Mutex<Option<ClockGuard>>,
+    // Keys down right now, for the bypass keys
+    held: Mutex<HashSet<Key>>,
+    // Bypass key press that was eaten, so is its release
+    swallowed: Mutex<HashSet<Key>>,
+    // Where popped events go, told to let go of its keys on reload
+    output: Mutex<Option<ALoop>>,
+    // When the oldest buffered event came in, for the match latency
+    buffered_since: AtomicI64,
+    // Set on shutdown: input is refused, then nothing more is coming
+    stopping: AtomicBool,
+    closed: AtomicBool,
+    metrics: Metrics,
 }
 
 impl KeyBuffer {
-    pub fn push(&self, key: UKey, action: Action) {
-        let event = Event {
-            key: key,
-            action: action,
-        };
-        if self.config.has_key(&event) {
-            self.push_channel.lock().unwrap().send(event).unwrap();
-        } else {
-            self._pop_channel_s.lock().unwrap().send(event).unwrap();
+    pub fn push(&self, key: impl Into<Key>, action: Action) {
+        self.push_at(key, action, Instant::now());
+    }
+
+    /// Like `push` for an input read from a device at `read_at`, which
+    /// the output measures its latency from
+    pub fn push_at(&self, key: impl Into<Key>, action: Action, read_at: Instant) {
+        let key = key.into();
+        let event = Event { key, action };
+        if self.stopping.load(Ordering::SeqCst) {
+            return;
         }
+        if self._bypass_keys(&event) {
+            return;
+        }
+        if self.is_paused() {
+            let sender = self._pop_channel_s.lock().unwrap();
+            sender.send_framed(event, passthrough(read_at)).unwrap();
+            return;
+        }
+        if let Some(mouse_keys) = self.mouse_keys.read().unwrap().as_ref()
+            && mouse_keys.handle(&event)
+        {
+            return;
+        }
+        self._process(event, read_at);
     }
+
+    /// Ends the input frame the events pushed since the last end came in,
+    /// the output syncs what it passed on from them at once
+    pub fn end_frame(&self) {
+        self._pop_channel_s.lock().unwrap().end_frame();
+    }
+
+    /// Waits for the next event, `None` once shut down and drained
     pub fn pop(&self) -> Option<Event> {
+        self.pop_stamped().map(|(event, _)| event)
+    }
+
+    /// `pop` with the stamp of the input behind the event, if any
+    pub fn pop_stamped(&self) -> Option<Stamped> {
+        loop {
+            match self.pop_output()? {
+                Popped::Event(stamped) | Popped::Framed(stamped) => return Some(stamped),
+                Popped::FrameEnd => {}
+            }
+        }
+    }
+
+    /// `pop_stamped` that tells the output where input frames end
+    pub fn pop_output(&self) -> Option<Popped> {
         let c = self.pop_channel.clone();
         let locked_c = c.lock().unwrap();
-        match locked_c.recv() {
-            Ok(event) => Some(event),
-            Err(_) => None,
+        loop {
+            match locked_c.recv_timeout(POP_POLL) {
+                Ok(popped) => return Some(popped),
+                Err(mpsc::RecvTimeoutError::Timeout) if !self.closed.load(Ordering::SeqCst) => {}
+                Err(_) => return locked_c.try_recv().ok(),
+            }
         }
     }
 
     pub fn try_pop(&self) -> Option<Event> {
+        self.try_pop_stamped().map(|(event, _)| event)
+    }
+
+    pub fn try_pop_stamped(&self) -> Option<Stamped> {
         let c = self.pop_channel.clone();
         let locked_c = c.lock().unwrap();
-        match locked_c.try_recv() {
-            Ok(event) => Some(event),
-            Err(_) => None,
+        loop {
+            match locked_c.try_recv().ok()? {
+                Popped::Event(stamped) | Popped::Framed(stamped) => return Some(stamped),
+                Popped::FrameEnd => {}
+            }
+        }
+    }
+
+    /// Latency of the events sent so far
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn config(&self) -> Arc<ParsedConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Swaps in a new config. Running actions are cancelled, binding
+    /// indexes of the old config mean nothing in the new one.
+    pub fn reload(&self, config: ParsedConfig) {
+        // Both held until the new config is in, so no event is matched and
+        // no action started against the old one after the reset
+        let mut deq = self.deque.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+        self._reset_locked(&mut deq, &mut running);
+        self.key_scheduler
+            .lock()
+            .unwrap()
+            .set_capacity(config.max_scheduled);
+        *self.mouse_keys.write().unwrap() = config.mouse_keys.clone().map(|mk| {
+            MouseKeys::new(mk, self.clock.clone(), self._pop_channel_s.clone())
+        });
+        self.layers
+            .write()
+            .unwrap()
+            .retain(|layer| config.has_layer(layer));
+        *self.config.write().unwrap() = Arc::new(config);
+        drop(running);
+        drop(deq);
+        // Cancelled macros may have left keys down
+        if let Err(e) = self.release_output() {
+            log_error!("Failed to release keys on reload: {e}");
+        }
+    }
+
+    /// Switches the bindings of `[layers.<name>]` on or off, false when the
+    /// config has no such layer
+    pub fn set_layer(&self, name: &str, on: bool) -> bool {
+        if !self.config().has_layer(name) {
+            return false;
+        }
+        let mut layers = self.layers.write().unwrap();
+        if on {
+            layers.insert(name.to_string());
+        } else {
+            layers.remove(name);
+        }
+        true
+    }
+
+    pub fn layers(&self) -> Layers {
+        self.layers.read().unwrap().clone()
+    }
+
+    /// Passes every key through untouched until `resume`, or until
+    /// `timeout_ms` is over
+    pub fn pause(&self, timeout_ms: Option<i64>) {
+        self.paused.store(true, Ordering::Relaxed);
+        let paused = self.paused.clone();
+        *self.resume_guard.lock().unwrap() = timeout_ms.map(|ms| {
+            self.clock.schedule_with_delay(
+                chrono::Duration::milliseconds(ms),
+                Box::new(move || paused.store(false, Ordering::Relaxed)),
+            )
+        });
+        self._reset();
+    }
+
+    pub fn resume(&self) {
+        self.resume_guard.lock().unwrap().take();
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Events waiting in the scheduler
+    pub fn scheduled(&self) -> usize {
+        self.key_scheduler.lock().unwrap().pending()
+    }
+
+    pub fn set_exec_runner(&self, exec: ExecRunner) {
+        self.key_scheduler.lock().unwrap().set_exec_runner(exec);
+    }
+
+    /// Stops taking input and winds down scheduled actions as `policy`
+    /// says. Blocks for `shutdown_flush_ms` at most.
+    pub fn shutdown(&self, policy: ShutdownPolicy) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.resume_guard.lock().unwrap().take();
+        if policy == ShutdownPolicy::Flush {
+            let config = self.config();
+            self._cancel_repeating(&config);
+            let flush = Duration::from_millis(config.shutdown_flush_ms);
+            let start = Instant::now();
+            while self.scheduled() > 0 && start.elapsed() < flush {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        self._reset();
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_output(&self, output: ALoop) {
+        *self.output.lock().unwrap() = Some(output);
+    }
+
+    /// Lets go of every key the output still holds down
+    pub fn release_output(&self) -> Result<(), Box<dyn Error>> {
+        let Some(output) = self.output.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        output.lock().unwrap().release_all()
+    }
+
+    /// Toggles the pause when the event completes the bypass keys, true if
+    /// the event is to be dropped
+    fn _bypass_keys(&self, event: &Event) -> bool {
+        let config = self.config();
+        let Some(bypass) = &config.bypass else {
+            return false;
+        };
+        if event.key.is_relative() {
+            return false;
+        }
+        let mut held = self.held.lock().unwrap();
+        match event.action {
+            Action::Press => held.insert(event.key),
+            Action::Release => {
+                held.remove(&event.key);
+                return self.swallowed.lock().unwrap().remove(&event.key);
+            }
+        };
+        if !bypass.keys.contains(&event.key) || !bypass.keys.iter().all(|k| held.contains(k)) {
+            return false;
+        }
+        drop(held);
+        self.swallowed.lock().unwrap().insert(event.key);
+        if self.is_paused() {
+            self.resume();
+        } else {
+            self.pause(bypass.timeout_ms);
+        }
+        true
+    }
+
+    /// Lets buffered keys out, stops running actions and lets go of
+    /// whatever mouse keys hold
+    fn _reset(&self) {
+        let mut deq = self.deque.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+        self._reset_locked(&mut deq, &mut running);
+    }
+
+    fn _reset_locked(&self, deq: &mut KeyDeque, running: &mut Running) {
+        for mut e in deq.drain(..) {
+            e.cancel();
+            let sender = self._pop_channel_s.lock().unwrap();
+            sender.send_stamped(e.event, passthrough(e.read_at)).unwrap();
+        }
+        let mut scheduler = self.key_scheduler.lock().unwrap();
+        for (_, macros) in running.drain() {
+            for id in macros {
+                scheduler.cancel(id);
+            }
+        }
+        if let Some(mouse_keys) = self.mouse_keys.read().unwrap().as_ref() {
+            mouse_keys.release_all();
         }
     }
 
-    fn _drop(self: Arc<Self>) {
+    /// Forgets the buffered events and what fired actions haven't sent yet
+    fn _drop(&self) {
         let mut deque = self.deque.lock().unwrap();
-        for el in deque.iter_mut() {
+        Self::_clear(&mut deque);
+        let mut running = self.running.lock().unwrap();
+        running.clear();
+        self.key_scheduler.lock().unwrap().clear();
+    }
+
+    fn _clear(deq: &mut KeyDeque) {
+        for el in deq.iter_mut() {
             el.cancel();
         }
-        deque.clear();
+        deq.clear();
     }
 }
 
 impl KeyBuffer {
-    fn _schedule_event(self: Arc<Self>, event: Event, delay: i64) {
-        debug_println!("Scheduled {:?} {}", event, delay);
-        let self_c = self.clone();
+    fn _schedule_event(&self, deq: &mut KeyDeque, event: Event, delay: i64, read_at: Instant) {
+        log_trace!("Buffered"; key = event.key, delay_ms = delay);
+        if deq.is_empty() {
+            self.buffered_since.store(self.clock.now_ms(), Ordering::SeqCst);
+        }
+        let deque = self.deque.clone();
+        let sender = self._pop_channel_s.clone();
         let be = BufferEvent {
-            event: event,
-            guard: Some(self.timer.schedule_with_delay(
+            event,
+            guard: Some(self.clock.schedule_with_delay(
                 chrono::Duration::milliseconds(delay),
-                move || {
-                    let mut dlq = self_c.deque.lock().unwrap();
+                Box::new(move || {
+                    let mut dlq = deque.lock().unwrap();
 
                     if let Some(e) = dlq.pop_front() {
-                        self_c._pop_channel_s.lock().unwrap().send(e.event).unwrap();
+                        let sender = sender.lock().unwrap();
+                        sender.send_stamped(e.event, passthrough(e.read_at)).unwrap();
                     }
-                },
+                }),
             )),
+            read_at,
         };
-        let mut dlq = self.deque.lock().unwrap();
-        dlq.push_back(be);
-    }
-
-    fn _start_listen(key_buffer: Arc<Self>) {
-        thread::spawn(move || {
-            let kb = key_buffer.clone();
-            let delay: u64 = kb.config.delay_ms.unwrap_or(DEFAULT_DELAY_MS);
-            loop {
-                if let Ok(received) = kb._push_channel_r.lock().unwrap().recv() {
-                    kb.clone()._schedule_event(received, delay as i64);
-                    debug_println!("Buffer size after push: {}", kb.deque.lock().unwrap().len());
-                    let deq = kb.deque.lock().unwrap();
-                    if let Some(action) = get_action(&deq, &kb.config.key_combinations) {
-                        println!("GOTCH!!");
-                        // Release deque mutex
-                        drop(deq);
-                        kb.clone()._drop();
-                        {
-                            macro_rules! try_schedule {
-                                ($scheduler:expr, $event:expr, $delay:expr) => {
-                                    if let Err(e) = $scheduler.schedule($event, $delay) {
-                                        eprintln!("Error scheduling event: {}", e);
-                                    }
-                                };
-                            }
-                            let mut locked_scheduler = kb.key_scheduler.lock().unwrap();
-                            let events = action_to_events(action);
-                            for (delay, event) in events {
-                                try_schedule!(locked_scheduler, event, delay);
-                            }
-                        }
-                        println!("GOTCH!aaaa!");
+        deq.push_back(be);
+    }
+
+    /// Sends buffered events that can no longer be part of any binding
+    /// straight to the output, oldest first, until the rest may still match.
+    fn _release_unmatched(&self, config: &ParsedConfig, deq: &mut KeyDeque) {
+        let layers = self.layers.read().unwrap();
+        while !config.can_match(deq, &layers) {
+            if let Some(mut e) = deq.pop_front() {
+                e.cancel();
+                log_trace!("Let out unmatched"; key = e.event.key);
+                let sender = self._pop_channel_s.lock().unwrap();
+                sender.send_stamped(e.event, passthrough(e.read_at)).unwrap();
+            }
+        }
+    }
+
+    fn _process(&self, event: Event, read_at: Instant) {
+        // Deque stays locked while the event is handled, so pushes from
+        // several threads are processed one at a time. Reload swaps the
+        // config under it too, one snapshot holds for the whole event.
+        let mut deq = self.deque.lock().unwrap();
+        let config = self.config();
+        match event.action {
+            // Pointer motion and wheel are not keypresses
+            Action::Press if !event.key.is_relative() => self._cancel_on_keypress(&config),
+            Action::Press => {}
+            Action::Release => self._stop_repeats(&config, event.key),
+        }
+        if !config.has_key(&event) {
+            // No binding goes on with it, whatever is buffered goes out first
+            let sender = self._pop_channel_s.lock().unwrap();
+            for mut e in deq.drain(..) {
+                e.cancel();
+                log_trace!("Let out unmatched"; key = e.event.key);
+                sender.send_stamped(e.event, passthrough(e.read_at)).unwrap();
+            }
+            sender.send_framed(event, passthrough(read_at)).unwrap();
+            return;
+        }
+        let delay: u64 = config.delay_ms.unwrap_or(DEFAULT_DELAY_MS);
+        self._schedule_event(&mut deq, event, delay as i64, read_at);
+        self._release_unmatched(&config, &mut deq);
+        let binding = config.binding(&deq, &self.layers.read().unwrap());
+        if let Some(binding) = binding {
+            Self::_clear(&mut deq);
+            // Release deque mutex
+            drop(deq);
+            let (layer, trigger, _) = config.bindings().nth(binding).unwrap();
+            log_debug!(
+                "Matched";
+                binding = trigger,
+                layer = layer.unwrap_or("main"),
+                latency_ms = self.clock.now_ms() - self.buffered_since.load(Ordering::SeqCst)
+            );
+            self._fire(&config, binding, read_at);
+        }
+    }
+
+    /// Whether `config` is still the one binding indexes in `running` refer to
+    fn _is_current(&self, config: &Arc<ParsedConfig>) -> bool {
+        Arc::ptr_eq(config, &self.config.read().unwrap())
+    }
+
+    /// Starts the action of a binding, minding a copy that still runs. Its
+    /// first key carries `read_at` of the input that completed the trigger.
+    fn _fire(&self, config: &Arc<ParsedConfig>, binding: usize, read_at: Instant) {
+        // Same lock order as everywhere else: running, then the scheduler
+        let mut running = self.running.lock().unwrap();
+        // Reloaded since the match, the reload cancelled what the old
+        // config started and the index means nothing now
+        if !self._is_current(config) {
+            return;
+        }
+        let mut scheduler = self.key_scheduler.lock().unwrap();
+        let macros = running.entry(binding).or_default();
+        macros.retain(|id| scheduler.is_running(*id));
+        if !macros.is_empty() {
+            match config.binding_options(binding).retrigger {
+                Retrigger::Overlap => {}
+                Retrigger::Restart => {
+                    for id in macros.drain(..) {
+                        scheduler.cancel(id);
                     }
                 }
+                Retrigger::Ignore => return,
             }
-        });
+        }
+        let events = action_to_outputs(config.action(binding), &config.action_options);
+        let id = match config.binding_options(binding).repeat_ms {
+            Some(interval) => scheduler.schedule_repeating(events, interval as i64, Some(read_at)),
+            None => scheduler.schedule_macro(events, Some(read_at)),
+        };
+        macros.push(id);
+    }
+
+    /// Cancels repeating actions whose trigger holds `key`
+    fn _stop_repeats(&self, config: &ParsedConfig, key: Key) {
+        let mut running = self.running.lock().unwrap();
+        if running.is_empty() {
+            return;
+        }
+        let mut scheduler = self.key_scheduler.lock().unwrap();
+        for (binding, macros) in running.iter_mut() {
+            if config.binding_options(*binding).repeat_ms.is_some()
+                && config.binding_presses(*binding, key)
+            {
+                for id in macros.drain(..) {
+                    scheduler.cancel(id);
+                }
+            }
+        }
+        running.retain(|_, macros| !macros.is_empty());
+    }
+
+    fn _cancel_repeating(&self, config: &Arc<ParsedConfig>) {
+        let mut running = self.running.lock().unwrap();
+        if !self._is_current(config) {
+            return;
+        }
+        let mut scheduler = self.key_scheduler.lock().unwrap();
+        for (binding, macros) in running.iter_mut() {
+            if config.binding_options(*binding).repeat_ms.is_some() {
+                for id in macros.drain(..) {
+                    scheduler.cancel(id);
+                }
+            }
+        }
+        running.retain(|_, macros| !macros.is_empty());
+    }
+
+    fn _cancel_on_keypress(&self, config: &ParsedConfig) {
+        let mut running = self.running.lock().unwrap();
+        if running.is_empty() {
+            return;
+        }
+        let mut scheduler = self.key_scheduler.lock().unwrap();
+        for (binding, macros) in running.iter_mut() {
+            if config.binding_options(*binding).cancel_on_keypress {
+                for id in macros.drain(..) {
+                    scheduler.cancel(id);
+                }
+            }
+        }
+        running.retain(|_, macros| !macros.is_empty());
     }
 
     pub fn new(app_config: ParsedConfig) -> Result<Arc<Self>, Box<dyn Error>> {
-        let c_in = mpsc::channel::<Event>();
-        let c_out = mpsc::channel::<Event>();
+        KeyBuffer::with_clock(app_config, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(
+        app_config: ParsedConfig,
+        clock: SafeClock,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
+        let c_out = mpsc::channel::<Popped>();
         macro_rules! make_recv {
             ($arg:expr) => {
                 Arc::new(Mutex::new($arg))
             };
         }
 
-        let push_channel_ptr = make_recv!(c_in.0);
+        let pop_channel_ptr: SafeSender = make_recv!(c_out.0);
+        let mut key_scheduler = KeyScheduler::with_clock(pop_channel_ptr.clone(), clock.clone())?;
+        key_scheduler.set_capacity(app_config.max_scheduled);
+        let mouse_keys = app_config
+            .mouse_keys
+            .clone()
+            .map(|mk| MouseKeys::new(mk, clock.clone(), pop_channel_ptr.clone()));
         let kb = Arc::new(KeyBuffer {
             deque: make_recv!(VecDeque::<BufferEvent>::with_capacity(KEY_CAPASITY)),
-            push_channel: push_channel_ptr.clone(),
-            _push_channel_r: make_recv!(c_in.1),
 
             pop_channel: make_recv!(c_out.1),
-            _pop_channel_s: make_recv!(c_out.0),
-            timer: timer::Timer::new(),
-            key_scheduler: make_recv!(KeyScheduler::new(push_channel_ptr.clone()).unwrap()),
-            config: app_config,
+            _pop_channel_s: pop_channel_ptr.clone(),
+            clock,
+            key_scheduler: make_recv!(key_scheduler),
+            running: Mutex::new(HashMap::new()),
+            mouse_keys: RwLock::new(mouse_keys),
+            config: RwLock::new(Arc::new(app_config)),
+            layers: RwLock::new(Layers::new()),
+            paused: Arc::new(AtomicBool::new(false)),
+            resume_guard: Mutex::new(None),
+            held: Mutex::new(HashSet::new()),
+            swallowed: Mutex::new(HashSet::new()),
+            output: Mutex::new(None),
+            buffered_since: AtomicI64::new(0),
+            stopping: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            metrics: Metrics::default(),
         });
-        KeyBuffer::_start_listen(kb.clone());
-        Ok(kb.clone())
+        Ok(kb)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::VirtualClock;
     use crate::config::config_from_str;
+    use crate::udev_loop::{MemorySink, OutputSink, Udev};
     use std::sync::Arc;
-    use std::time::Duration;
+
+    fn virtual_buffer(config: &str) -> (Arc<KeyBuffer>, Arc<VirtualClock>) {
+        let clock = VirtualClock::new();
+        let buf = KeyBuffer::with_clock(config_from_str(config), clock.clone()).unwrap();
+        (buf, clock)
+    }
 
     #[test]
     fn test_buffer() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+        [main]
+        "a down + b up + c up" = "b"
+        "#,
+        );
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::B, Action::Release);
+        buf.push(UKey::C, Action::Release);
+        clock.advance(0);
+
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::B),
+                action: Action::Press
+            })
+        );
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::B),
+                action: Action::Release
+            })
+        );
+        assert_eq!(buf.try_pop(), None);
+    }
+
+    #[test]
+    fn test_buffer_system_clock() {
         let cnf = config_from_str(
             r#"
         [main]
@@ -221,14 +762,14 @@ mod tests {
         assert_eq!(
             buf.pop(),
             Some(Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::B),
                 action: Action::Press
             })
         );
         assert_eq!(
             buf.pop(),
             Some(Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::B),
                 action: Action::Release
             })
         );
@@ -237,113 +778,568 @@ mod tests {
 
     #[test]
     fn test_buffer_delay() {
-        let cnf = config_from_str(
+        let (buf, clock) = virtual_buffer(
             r#"
             delay_ms=5
             [main]
         "a" = "b"
         "#,
         );
-        let buf = KeyBuffer::new(cnf).unwrap();
         buf.push(UKey::A, Action::Press);
-        thread::sleep(Duration::from_millis(10));
-        buf.push(UKey::A, Action::Release);
-        thread::sleep(Duration::from_millis(10));
+        clock.advance(4);
+        assert_eq!(buf.try_pop(), None);
+        clock.advance(1);
         assert_eq!(
             buf.try_pop(),
             Some(Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press
             })
         );
+        clock.advance(5);
+        buf.push(UKey::A, Action::Release);
+        clock.advance(10);
         assert_eq!(
             buf.try_pop(),
             Some(Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Release
             })
         );
         assert_eq!(buf.deque.lock().unwrap().len(), 0);
 
-        let cnf = config_from_str(
+        let (buf, clock) = virtual_buffer(
             r#"
             delay_ms=12
             [main]
         "a" = "b"
         "#,
         );
-        let buf = KeyBuffer::new(cnf).unwrap();
         buf.push(UKey::A, Action::Press);
-        thread::sleep(Duration::from_millis(10));
+        clock.advance(10);
+        buf.push(UKey::A, Action::Release);
+        clock.advance(0);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::B),
+                action: Action::Press
+            })
+        );
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::B),
+                action: Action::Release
+            })
+        );
+        assert_eq!(buf.deque.lock().unwrap().len(), 0);
+        assert_eq!(clock.pending(), 0);
+    }
+    #[test]
+    fn test_buffer_release_unmatched() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+        "a down + b down" = "c"
+        "d down + e down" = "f"
+        "#,
+        );
+        buf.push(UKey::A, Action::Press);
+        assert_eq!(buf.try_pop(), None);
+
+        // "a down + d down" can't complete any binding, "a" goes out immediately
+        buf.push(UKey::D, Action::Press);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Press
+            })
+        );
+        assert_eq!(buf.try_pop(), None);
+
+        buf.push(UKey::E, Action::Press);
+        clock.advance(0);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::F),
+                action: Action::Press
+            })
+        );
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::F),
+                action: Action::Release
+            })
+        );
+        assert_eq!(buf.deque.lock().unwrap().len(), 0);
+
+        // Chords take their keys in any order, a plain "a" waits out the delay
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+        "leftctrl + a" = "b"
+        "#,
+        );
+        buf.push(UKey::A, Action::Press);
         buf.push(UKey::A, Action::Release);
-        thread::sleep(Duration::from_millis(15));
+        assert_eq!(buf.try_pop(), None);
+        clock.advance(1000);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Press
+            })
+        );
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Release
+            })
+        );
+    }
+
+    #[test]
+    fn test_buffer_chord_any_order() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+        "leftmeta + leftshift + F23" = "b"
+        "#,
+        );
+        buf.push(UKey::LeftShift, Action::Press);
+        buf.push(UKey::F23, Action::Press);
+        assert_eq!(buf.try_pop(), None);
+        buf.push(UKey::LeftMeta, Action::Press);
+        buf.push(UKey::F23, Action::Release);
+        buf.push(UKey::LeftMeta, Action::Release);
+        buf.push(UKey::LeftShift, Action::Release);
+        clock.advance(0);
         assert_eq!(
             buf.try_pop(),
             Some(Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::B),
                 action: Action::Press
             })
         );
         assert_eq!(
             buf.try_pop(),
             Some(Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::B),
                 action: Action::Release
             })
         );
+        assert_eq!(buf.try_pop(), None);
+    }
+
+    #[test]
+    fn test_buffer_unbound_after_buffered() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+        "leftctrl + a" = "b"
+        "#,
+        );
+        // Ctrl is held back for the chord, C doesn't overtake it
+        buf.push(UKey::LeftControl, Action::Press);
+        assert_eq!(buf.try_pop(), None);
+        buf.push(UKey::C, Action::Press);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::LeftControl),
+                action: Action::Press
+            })
+        );
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::C),
+                action: Action::Press
+            })
+        );
+        // Its timeout was cancelled with the flush
+        clock.advance(2000);
+        assert_eq!(buf.try_pop(), None);
         assert_eq!(buf.deque.lock().unwrap().len(), 0);
     }
+
     #[test]
     fn test_buffer_drop() {
-        let cnf = config_from_str(
+        let (buf, clock) = virtual_buffer(
             r#"
             [main]
         "a down + b up + c up" = "b"
         "#,
         );
-        let buf = KeyBuffer::new(cnf).unwrap();
         buf.push(UKey::A, Action::Press);
         buf.push(UKey::B, Action::Release);
         buf.push(UKey::C, Action::Release);
-        thread::sleep(Duration::from_millis(1));
-        buf.clone()._drop();
-        thread::sleep(Duration::from_millis(300));
+        buf._drop();
+        clock.advance(300);
         assert_eq!(buf.try_pop(), None);
         assert_eq!(buf.deque.lock().unwrap().len(), 0);
     }
 
     #[test]
-    fn test_timer_simple() {
-        let timer = timer::Timer::new();
-        println!("schedule");
-        let _g = timer.schedule_with_delay(chrono::Duration::milliseconds(100), move || {
-            println!("WEFWEFWEF");
-        });
+    fn test_buffer_drop_unmatched() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=100
+            [main]
+        "a down + b up + c up" = "b"
+        "#,
+        );
+        // "a down + a down" is no prefix, the first one is out before the drop
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::B, Action::Release);
+        clock.advance(10);
+        buf._drop();
+        clock.advance(300);
+        assert_eq!(
+            buf.try_pop(),
+            Some(Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Press
+            })
+        );
+        // The live prefix was dropped
+        assert_eq!(buf.try_pop(), None);
+        assert_eq!(buf.deque.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_buffer_retrigger() {
+        let config = |options: &str| {
+            format!(
+                r#"
+            delay_ms=1000
+            [main]
+            "a down" = {{ action = "leftctrl down + wait 300 + leftctrl up"{options} }}
+            "#
+            )
+        };
+        let ctrl = |action| Event {
+            key: Key::Keyboard(UKey::LeftControl),
+            action,
+        };
+        let drain = |buf: &KeyBuffer| std::iter::from_fn(|| buf.try_pop()).collect::<Vec<_>>();
+
+        let (buf, clock) = virtual_buffer(&config(""));
+        buf.push(UKey::A, Action::Press);
+        clock.advance(100);
+        buf.push(UKey::A, Action::Press);
+        clock.advance(400);
+        // Two copies overlap
+        assert_eq!(
+            drain(&buf),
+            vec![
+                ctrl(Action::Press),
+                ctrl(Action::Press),
+                ctrl(Action::Release),
+                ctrl(Action::Release)
+            ]
+        );
 
-        thread::sleep(Duration::from_secs(1));
+        let (buf, clock) = virtual_buffer(&config(r#", retrigger = "restart""#));
+        buf.push(UKey::A, Action::Press);
+        clock.advance(100);
+        buf.push(UKey::A, Action::Press);
+        assert_eq!(drain(&buf), vec![ctrl(Action::Press), ctrl(Action::Release)]);
+        clock.advance(300);
+        assert_eq!(drain(&buf), vec![ctrl(Action::Press), ctrl(Action::Release)]);
+
+        let (buf, clock) = virtual_buffer(&config(r#", retrigger = "ignore""#));
+        buf.push(UKey::A, Action::Press);
+        clock.advance(100);
+        buf.push(UKey::A, Action::Press);
+        clock.advance(400);
+        assert_eq!(drain(&buf), vec![ctrl(Action::Press), ctrl(Action::Release)]);
+        // Finished, fires again
+        buf.push(UKey::A, Action::Press);
+        clock.advance(0);
+        assert_eq!(drain(&buf), vec![ctrl(Action::Press)]);
     }
 
     #[test]
-    fn test_timer() {
-        let timer = timer::Timer::new();
-
-        let mut guards = Vec::new();
-        let cntr = Arc::new(Mutex::new(0));
-        for _ in 0..10_000 {
-            let counter = Arc::clone(&cntr);
-            let guard = timer.schedule_with_delay(chrono::Duration::milliseconds(1), move || {
-                println!("Timer dingg!! after {} ms", 10);
-                let mut num = counter.lock().unwrap();
-                *num += 1;
-            });
-            guards.push(guard);
-        }
-        for g in guards {
-            drop(g);
-        }
-        println!("Events {}", cntr.lock().unwrap());
-        thread::sleep(Duration::from_secs(1));
+    fn test_buffer_cancel_on_keypress() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+            "a down" = { action = "leftctrl down + wait 300 + leftctrl up", cancel_on_keypress = true }
+            "#,
+        );
+        buf.push(UKey::A, Action::Press);
+        clock.advance(100);
+        // Releases don't cancel
+        buf.push(UKey::A, Action::Release);
+        buf.push(UKey::X, Action::Press);
+        assert_eq!(
+            std::iter::from_fn(|| buf.try_pop()).collect::<Vec<_>>(),
+            vec![
+                Event {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Action::Press
+                },
+                Event {
+                    key: Key::Keyboard(UKey::A),
+                    action: Action::Release
+                },
+                Event {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Action::Release
+                },
+                Event {
+                    key: Key::Keyboard(UKey::X),
+                    action: Action::Press
+                },
+            ]
+        );
+        clock.advance(500);
+        assert_eq!(buf.try_pop(), None);
+    }
+
+    #[test]
+    fn test_buffer_repeat_while_held() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+            "f13 down" = { action = "down", repeat_ms = 30 }
+            "#,
+        );
+        let presses = |buf: &KeyBuffer| {
+            std::iter::from_fn(|| buf.try_pop())
+                .filter(|e| e.key == UKey::Down.into() && e.action == Action::Press)
+                .count()
+        };
+        buf.push(UKey::F13, Action::Press);
+        clock.advance(100);
+        // At 0, 30, 60 and 90
+        assert_eq!(presses(&buf), 4);
+
+        // Other keys keep it going
+        buf.push(UKey::X, Action::Press);
+        buf.push(UKey::X, Action::Release);
+        clock.advance(20);
+        assert_eq!(presses(&buf), 1);
+
+        buf.push(UKey::F13, Action::Release);
+        clock.advance(200);
+        assert_eq!(presses(&buf), 0);
+        assert_eq!(clock.pending(), 0);
+    }
+
+    #[test]
+    fn test_buffer_mouse_keys() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            [main]
+            "a" = "b"
+            [mouse_keys]
+            layer = "capslock"
+            tick_ms = 10
+            start_speed = 3
+            max_speed = 3
+            scroll_ms = 25
+            "#,
+        );
+        let press = |key| Event {
+            key,
+            action: Action::Press,
+        };
+        let release = |key| Event {
+            key,
+            action: Action::Release,
+        };
+        let pop_all = |buf: &KeyBuffer| std::iter::from_fn(|| buf.try_pop()).collect::<Vec<_>>();
+
+        buf.push(UKey::L, Action::Press);
+        buf.push(UKey::L, Action::Release);
+        assert_eq!(
+            pop_all(&buf),
+            vec![press(UKey::L.into()), release(UKey::L.into())]
+        );
+
+        buf.push(UKey::CapsLock, Action::Press);
+        buf.push(UKey::L, Action::Press);
+        clock.advance(20);
+        buf.push(UKey::K, Action::Press);
+        clock.advance(10);
+        buf.push(UKey::F, Action::Press);
+        buf.push(UKey::X, Action::Press);
+        assert_eq!(
+            pop_all(&buf),
+            vec![
+                press(Key::Move(Axis::X, 3)),
+                press(Key::Move(Axis::X, 3)),
+                press(Key::Move(Axis::X, 3)),
+                press(Key::Move(Axis::Y, -3)),
+                press(UButton::Left.into()),
+                press(UKey::X.into()),
+            ]
+        );
+
+        // Letting go of the layer lets go of everything it holds
+        buf.push(UKey::CapsLock, Action::Release);
+        buf.push(UKey::L, Action::Release);
+        buf.push(UKey::F, Action::Release);
+        clock.advance(100);
+        assert_eq!(pop_all(&buf), vec![release(UButton::Left.into())]);
+        assert_eq!(clock.pending(), 0);
+
+        buf.push(UKey::CapsLock, Action::Press);
+        buf.push(UKey::N, Action::Press);
+        clock.advance(60);
+        buf.push(UKey::N, Action::Release);
+        clock.advance(100);
+        assert_eq!(
+            pop_all(&buf),
+            vec![press(Key::Scroll(Axis::Y, -1)); 3]
+        );
+    }
+
+    #[test]
+    fn test_buffer_layers_and_pause() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms = 1000
+            [main]
+            "a" = "b"
+            [layers.nav]
+            "h" = "left"
+            "a" = "c"
+            "#,
+        );
+        let keys = |buf: &KeyBuffer| {
+            std::iter::from_fn(|| buf.try_pop())
+                .filter(|e| e.action == Action::Press)
+                .map(|e| e.key)
+                .collect::<Vec<_>>()
+        };
+        let tap = |key: UKey| {
+            buf.push(key, Action::Press);
+            buf.push(key, Action::Release);
+            clock.advance(10);
+        };
+
+        // Keys of a layer that is off go through without waiting
+        tap(UKey::H);
+        tap(UKey::A);
+        assert_eq!(keys(&buf), vec![UKey::H.into(), UKey::B.into()]);
+
+        assert!(buf.set_layer("nav", true));
+        assert!(!buf.set_layer("sym", true));
+        tap(UKey::H);
+        tap(UKey::A);
+        assert_eq!(keys(&buf), vec![UKey::Left.into(), UKey::C.into()]);
+
+        buf.pause(Some(500));
+        tap(UKey::A);
+        assert_eq!(keys(&buf), vec![UKey::A.into()]);
+        clock.advance(500);
+        assert!(!buf.is_paused());
+        buf.set_layer("nav", false);
+        tap(UKey::A);
+        assert_eq!(keys(&buf), vec![UKey::B.into()]);
+    }
+
+    #[test]
+    fn test_buffer_bypass_keys() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms = 1000
+            [main]
+            "a" = "b"
+            [bypass]
+            keys = "leftctrl + rightctrl"
+            timeout_s = 60
+            "#,
+        );
+        let keys = |buf: &KeyBuffer| {
+            std::iter::from_fn(|| buf.try_pop())
+                .map(|e| (e.key, e.action))
+                .collect::<Vec<_>>()
+        };
+        let bypass = || {
+            buf.push(UKey::LeftControl, Action::Press);
+            buf.push(UKey::RightControl, Action::Press);
+            buf.push(UKey::RightControl, Action::Release);
+            buf.push(UKey::LeftControl, Action::Release);
+        };
+
+        bypass();
+        assert!(buf.is_paused());
+        // Only the key completing the combo is eaten
+        assert_eq!(
+            keys(&buf),
+            vec![
+                (UKey::LeftControl.into(), Action::Press),
+                (UKey::LeftControl.into(), Action::Release),
+            ]
+        );
+        buf.push(UKey::A, Action::Press);
+        assert_eq!(keys(&buf), vec![(UKey::A.into(), Action::Press)]);
+        buf.push(UKey::A, Action::Release);
+
+        bypass();
+        assert!(!buf.is_paused());
+        keys(&buf);
+
+        bypass();
+        clock.advance(60_000);
+        assert!(!buf.is_paused());
+    }
+
+    #[test]
+    fn test_buffer_mouse_output() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            delay_ms=1000
+            [main]
+            "f13 down" = { action = "mouse_click right + mouse_move 5 -5 + scroll down 2 + mouse_left down + wait 100 + mouse_left up", cancel_on_keypress = true }
+            "#,
+        );
+        buf.push(UKey::F13, Action::Press);
+        clock.advance(50);
+        let press = |key| Event {
+            key,
+            action: Action::Press,
+        };
+        let release = |key| Event {
+            key,
+            action: Action::Release,
+        };
+        assert_eq!(
+            std::iter::from_fn(|| buf.try_pop()).collect::<Vec<_>>(),
+            vec![
+                press(Key::Button(UButton::Right)),
+                release(Key::Button(UButton::Right)),
+                press(Key::Move(Axis::X, 5)),
+                press(Key::Move(Axis::Y, -5)),
+                press(Key::Scroll(Axis::Y, -2)),
+                press(Key::Button(UButton::Left)),
+            ]
+        );
+        // Only the held button is released on cancel
+        buf.push(UKey::X, Action::Press);
+        assert_eq!(
+            std::iter::from_fn(|| buf.try_pop()).collect::<Vec<_>>(),
+            vec![release(Key::Button(UButton::Left)), press(UKey::X.into())]
+        );
     }
+
     macro_rules! dashes {
         () => {
             let dashes = "-".repeat(50);
@@ -357,38 +1353,41 @@ mod tests {
         let mut v = VecDeque::<BufferEvent>::new();
         let a = [
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Release,
             },
         ];
         v.push_back(BufferEvent {
             event: Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             guard: None,
+            read_at: Instant::now(),
         });
         v.push_back(BufferEvent {
             event: Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             guard: None,
+            read_at: Instant::now(),
         });
         v.push_back(BufferEvent {
             event: Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Release,
             },
             guard: None,
+            read_at: Instant::now(),
         });
         println!("{:?}", v);
 
@@ -399,4 +1398,142 @@ mod tests {
         }
         dashes!();
     }
+
+    #[test]
+    fn test_buffer_reload_releases_output() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            [main]
+            "a" = "leftctrl down"
+            "#,
+        );
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        buf.set_output(sink.clone());
+        let send = |buf: &KeyBuffer| {
+            while let Some(event) = buf.try_pop() {
+                sink.lock().unwrap().send_event(event).unwrap();
+            }
+        };
+
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::A, Action::Release);
+        clock.advance(10);
+        send(&buf);
+        // Nothing in the new config would ever let go of it
+        buf.reload(config_from_str("[main]\n\"b\" = \"c\"\n"));
+        clock.advance(1000);
+        send(&buf);
+        assert_eq!(
+            sink.lock().unwrap().events,
+            vec![
+                Event {
+                    key: UKey::LeftControl.into(),
+                    action: Action::Press,
+                },
+                Event {
+                    key: UKey::LeftControl.into(),
+                    action: Action::Release,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buffer_fire_after_reload() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            [main]
+            "a down" = { action = "b", repeat_ms = 100 }
+            "#,
+        );
+        // Matched just before a reload swapped in a config with no binding 0
+        let old = buf.config();
+        buf.reload(config_from_str("[main]\n"));
+        buf._fire(&old, 0, Instant::now());
+        clock.advance(500);
+        assert_eq!(buf.try_pop(), None);
+        assert!(buf.running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_shutdown() {
+        let config = r#"
+            shutdown_flush_ms = 5000
+            [main]
+            "a" = "leftctrl down + wait 300 + x + leftctrl up"
+            "b down" = { action = "y", repeat_ms = 10 }
+            "#;
+        let press = |key: UKey| Event {
+            key: key.into(),
+            action: Action::Press,
+        };
+        let release = |key: UKey| Event {
+            key: key.into(),
+            action: Action::Release,
+        };
+
+        // Cancelled right away, the macro is cut short
+        let (buf, clock) = virtual_buffer(config);
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::A, Action::Release);
+        clock.advance(10);
+        buf.shutdown(ShutdownPolicy::Cancel);
+        buf.push(UKey::C, Action::Press);
+        let events: Vec<_> = std::iter::from_fn(|| buf.pop()).collect();
+        assert_eq!(
+            events,
+            vec![press(UKey::LeftControl), release(UKey::LeftControl)]
+        );
+
+        // Flushed, the macro runs to its end while the repeat is stopped
+        let (buf, clock) = virtual_buffer(config);
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        let output = Udev::start_listen(sink.clone(), buf.clone(), None);
+        buf.push(UKey::B, Action::Press);
+        buf.push(UKey::A, Action::Press);
+        buf.push(UKey::A, Action::Release);
+        clock.advance(10);
+        let advance = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            clock.advance(1000);
+        });
+        buf.shutdown(ShutdownPolicy::Flush);
+        advance.join().unwrap();
+        output.join().unwrap();
+        let events = &sink.lock().unwrap().events;
+        let x = events.iter().position(|e| *e == press(UKey::X)).unwrap();
+        assert!(!events[x..].contains(&press(UKey::Y)));
+        assert_eq!(events.last(), Some(&release(UKey::LeftControl)));
+    }
+
+    #[test]
+    fn test_buffer_stamps() {
+        let (buf, clock) = virtual_buffer(
+            r#"
+            [main]
+            "a" = "x + y"
+            "#,
+        );
+        let typed = Instant::now();
+        buf.push_at(UKey::Z, Action::Press, typed);
+        buf.push_at(UKey::A, Action::Press, typed + Duration::from_millis(1));
+        // The release completes the trigger
+        let trigger = typed + Duration::from_millis(2);
+        buf.push_at(UKey::A, Action::Release, trigger);
+        clock.advance(10);
+        let popped: Vec<_> = std::iter::from_fn(|| buf.try_pop_stamped())
+            .map(|(event, stamp)| (event.key, stamp.map(|s| (s.path, s.read_at))))
+            .collect();
+        // Only the first key of an action stands for the trigger
+        assert_eq!(
+            popped,
+            vec![
+                (UKey::Z.into(), Some((Path::Passthrough, typed))),
+                (UKey::X.into(), Some((Path::Remapped, trigger))),
+                (UKey::X.into(), None),
+                (UKey::Y.into(), None),
+                (UKey::Y.into(), None),
+            ]
+        );
+    }
 }
@@ -0,0 +1,79 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::config::EmergencyExit;
+use crate::key_buffer::{Action, Event, Key};
+
+/// Watches raw input for the emergency exit keys
+pub struct EmergencyWatch {
+    combo: EmergencyExit,
+    held: HashSet<Key>,
+    // When the keys were last completed, oldest first
+    hits: VecDeque<i64>,
+}
+
+impl EmergencyWatch {
+    pub fn new(combo: EmergencyExit) -> Self {
+        EmergencyWatch {
+            combo,
+            held: HashSet::new(),
+            hits: VecDeque::new(),
+        }
+    }
+
+    /// True once the keys have been completed often enough
+    pub fn feed(&mut self, event: &Event, now_ms: i64) -> bool {
+        if event.action == Action::Release {
+            self.held.remove(&event.key);
+            return false;
+        }
+        self.held.insert(event.key);
+        // The press of any of the keys while the rest are down counts
+        if !self.combo.keys.contains(&event.key)
+            || !self.combo.keys.iter().all(|k| self.held.contains(k))
+        {
+            return false;
+        }
+        self.hits.push_back(now_ms);
+        while self
+            .hits
+            .front()
+            .is_some_and(|t| now_ms - t >= self.combo.window_ms)
+        {
+            self.hits.pop_front();
+        }
+        self.hits.len() >= self.combo.presses as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_buffer::UKey;
+
+    #[test]
+    fn test_emergency_watch() {
+        let mut watch = EmergencyWatch::new(EmergencyExit::default());
+        let mut feed = |key: UKey, action, at| {
+            watch.feed(
+                &Event {
+                    key: key.into(),
+                    action,
+                },
+                at,
+            )
+        };
+        // Esc alone and backspace alone are nothing
+        assert!(!feed(UKey::Esc, Action::Press, 0));
+        assert!(!feed(UKey::Esc, Action::Release, 0));
+
+        assert!(!feed(UKey::BackSpace, Action::Press, 0));
+        assert!(!feed(UKey::Esc, Action::Press, 100));
+        assert!(!feed(UKey::Esc, Action::Release, 150));
+        assert!(!feed(UKey::Esc, Action::Press, 200));
+        assert!(!feed(UKey::Esc, Action::Release, 250));
+        // Too late for the first one
+        assert!(!feed(UKey::Esc, Action::Press, 2100));
+        assert!(!feed(UKey::Esc, Action::Release, 2120));
+        assert!(feed(UKey::Esc, Action::Press, 2150));
+    }
+}
@@ -110,6 +110,24 @@ macro_rules! evdev_to_uinput_key {
     };
 }
 
+/// Mouse button of an evdev `BTN_*` key, `None` for every other key
+#[macro_export]
+macro_rules! evdev_to_uinput_button {
+    ($key:expr) => {
+        match $key {
+            Key::BTN_LEFT => Some(UButton::Left),
+            Key::BTN_RIGHT => Some(UButton::Right),
+            Key::BTN_MIDDLE => Some(UButton::Middle),
+            Key::BTN_SIDE => Some(UButton::Side),
+            Key::BTN_EXTRA => Some(UButton::Extra),
+            Key::BTN_FORWARD => Some(UButton::Forward),
+            Key::BTN_BACK => Some(UButton::Back),
+            Key::BTN_TASK => Some(UButton::Task),
+            _ => None,
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! mmm {
     () => {
@@ -135,4 +153,12 @@ mod tests {
     fn test_evdev_to_uinput_key_default() {
         assert_eq!(evdev_to_uinput_key!(Key::KEY_UNKNOWN), UKey::A);
     }
+
+    #[test]
+    fn test_evdev_to_uinput_button() {
+        use uinput::event::controller::Mouse as UButton;
+        assert_eq!(evdev_to_uinput_button!(Key::BTN_SIDE), Some(UButton::Side));
+        assert_eq!(evdev_to_uinput_button!(Key::BTN_EXTRA), Some(UButton::Extra));
+        assert_eq!(evdev_to_uinput_button!(Key::KEY_A), None);
+    }
 }
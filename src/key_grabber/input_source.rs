@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+use evdev::{Device, EventType, InputEvent, Key, Synchronization};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::log_warn;
+
+/// Where raw input events come from
+pub trait InputSource {
+    /// Next event, `None` once the source has nothing more to give
+    fn next_event(&mut self) -> Result<Option<InputEvent>, Box<dyn Error>>;
+    /// Hands the device back to everyone else
+    fn ungrab(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    /// Makes `next_event` give `None` soon after `stop` is set, sources
+    /// that never wait long can ignore it
+    fn stop_on(&mut self, _stop: Arc<AtomicBool>) {}
+    /// When the last event was read, output latency is measured from it
+    fn read_at(&self) -> Instant {
+        Instant::now()
+    }
+    /// Names the source in logs
+    fn label(&self) -> &str {
+        "memory"
+    }
+    /// Way to ungrab from another thread, for devices that are grabbed
+    fn grab_handle(&self) -> Option<GrabHandle> {
+        None
+    }
+}
+
+// _IOW('E', 0x90, int)
+const EVIOCGRAB: u64 = 0x40044590;
+
+/// Ungrabs a device from any thread, even while the thread reading it is
+/// stuck. Holds a duplicate of the device's descriptor, the grab belongs
+/// to the open file both share.
+pub struct GrabHandle(OwnedFd);
+
+impl GrabHandle {
+    pub fn ungrab(&self) -> std::io::Result<()> {
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), EVIOCGRAB as _, 0) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// How often a source waiting for input looks at its stop flag
+const STOP_POLL_MS: i32 = 50;
+
+/// evdev keyboard, grabbed unless only watched
+pub struct EvdevSource {
+    path: String,
+    device: Device,
+    pending: VecDeque<InputEvent>,
+    grabbed: bool,
+    stop: Option<Arc<AtomicBool>>,
+    read_at: Instant,
+}
+
+impl EvdevSource {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        // Auto exit after 30 seconds, safety measure to not dead lock keyboard input
+        #[cfg(debug_assertions)]
+        std::thread::spawn(move || {
+            const EXIT_S: u64 = 30;
+            log_warn!("Debug build, exiting in {EXIT_S} seconds");
+            std::thread::sleep(std::time::Duration::from_secs(EXIT_S));
+            std::process::exit(0);
+        });
+
+        let mut device = Device::open(path).expect("Failed to capture device");
+        device.grab()?;
+        Ok(EvdevSource {
+            path: path.to_string(),
+            device,
+            pending: VecDeque::new(),
+            grabbed: true,
+            stop: None,
+            read_at: Instant::now(),
+        })
+    }
+
+    /// Reads the device without grabbing it, input keeps reaching other clients
+    pub fn watch(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(EvdevSource {
+            path: path.to_string(),
+            device: Device::open(path)?,
+            pending: VecDeque::new(),
+            grabbed: false,
+            stop: None,
+            read_at: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether another client holds the grab of a watched device, it gets
+    /// the input and nothing reaches us. Tries to grab and lets go again.
+    pub fn grabbed_elsewhere(&mut self) -> Result<bool, Box<dyn Error>> {
+        match self.device.grab() {
+            Ok(()) => {
+                self.device.ungrab()?;
+                Ok(false)
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// True once the device has something to read, false after `timeout_ms`
+    fn wait_readable(&self, timeout_ms: i32) -> Result<bool, Box<dyn Error>> {
+        let mut fd = libc::pollfd {
+            fd: self.device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, timeout_ms) } {
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                Err(e.into())
+            }
+            n => Ok(n > 0),
+        }
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn next_event(&mut self) -> Result<Option<InputEvent>, Box<dyn Error>> {
+        while self.pending.is_empty() {
+            if let Some(stop) = &self.stop {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                if !self.wait_readable(STOP_POLL_MS)? {
+                    continue;
+                }
+            }
+            self.pending.extend(self.device.fetch_events()?);
+            self.read_at = Instant::now();
+        }
+        let event = self.pending.pop_front();
+        #[cfg(debug_assertions)]
+        if let Some(e) = &event
+            && self.grabbed
+            && e.kind() == evdev::InputEventKind::Key(Key::KEY_ESC)
+        {
+            std::process::exit(0);
+        }
+        Ok(event)
+    }
+
+    fn ungrab(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.grabbed {
+            self.device.ungrab()?;
+            self.grabbed = false;
+        }
+        Ok(())
+    }
+
+    fn stop_on(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn read_at(&self) -> Instant {
+        self.read_at
+    }
+
+    fn label(&self) -> &str {
+        &self.path
+    }
+
+    fn grab_handle(&self) -> Option<GrabHandle> {
+        if !self.grabbed {
+            return None;
+        }
+        let fd = unsafe { BorrowedFd::borrow_raw(self.device.as_raw_fd()) };
+        fd.try_clone_to_owned().ok().map(GrabHandle)
+    }
+}
+
+/// Fixed list of events, for running the pipeline without a device
+#[derive(Default)]
+pub struct MemorySource {
+    events: VecDeque<InputEvent>,
+}
+
+impl MemorySource {
+    pub fn new(events: Vec<InputEvent>) -> Self {
+        MemorySource {
+            events: events.into(),
+        }
+    }
+
+    /// Key events with evdev values: 0 release, 1 press, 2 autorepeat,
+    /// each in a frame of its own like a keyboard reports them
+    pub fn from_keys(keys: &[(Key, i32)]) -> Self {
+        MemorySource::new(
+            keys.iter()
+                .flat_map(|(key, value)| {
+                    [
+                        InputEvent::new(EventType::KEY, key.code(), *value),
+                        InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0),
+                    ]
+                })
+                .collect(),
+        )
+    }
+}
+
+impl InputSource for MemorySource {
+    fn next_event(&mut self) -> Result<Option<InputEvent>, Box<dyn Error>> {
+        Ok(self.events.pop_front())
+    }
+}
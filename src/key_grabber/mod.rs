@@ -1,49 +1,380 @@
-use evdev::{Device, InputEventKind, Key};
+use evdev::{InputEvent, InputEventKind, Key, RelativeAxisType, Synchronization};
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Instant;
+use uinput::event::controller::Mouse as UButton;
 use uinput::event::keyboard::Key as UKey;
 
+mod emergency;
 mod evdev_to_input;
+mod input_source;
 
-use crate::key_buffer::{Action, KeyBuffer};
+use crate::key_buffer::{self, Action, Axis, Event, KeyBuffer};
+use crate::udev_loop::POINTER_NAME;
+use crate::watchdog::Watchdog;
 
-use crate::debug_println;
+use crate::log_trace;
+pub use crate::evdev_to_uinput_button;
 pub use crate::evdev_to_uinput_key;
+pub use emergency::EmergencyWatch;
+pub use input_source::{EvdevSource, InputSource};
+#[allow(unused_imports)]
+pub use input_source::MemorySource;
 
-const DEVICE_PATH: &str = "/dev/input/event3";
-
-pub fn grab_kb_events(buffer: Arc<KeyBuffer>) -> Result<(), Box<dyn Error>> {
-    // Auto exit after 20 seconds, safety measure to not dead lock keyboard input
-    #[cfg(debug_assertions)]
-    std::thread::spawn(move || {
-        const EXIT_S: u64 = 30;
-        println!("Start safe thread, will exit in {} seconds", EXIT_S);
-        std::thread::sleep(std::time::Duration::from_secs(EXIT_S));
-        println!("safe thread exit");
-        std::process::exit(0);
-    });
-
-    let mut dev = Device::open(DEVICE_PATH).expect("Failed to capture device");
-    dev.grab()?;
-    loop {
-        for event in dev.fetch_events()? {
-            if let InputEventKind::Key(key) = event.kind() {
-                let uinput_key: UKey = evdev_to_uinput_key!(key);
-                debug_println!("evdev {:?} {}", uinput_key, event.value());
-                #[cfg(debug_assertions)]
-                if uinput_key == UKey::Esc {
-                    std::process::exit(0);
-                }
-                if event.value() == 0 || event.value() == 1 {
-                    let action = if event.value() == 0 {
-                        Action::Release
-                    } else {
-                        Action::Press
-                    };
-                    buffer.push(uinput_key, action);
-                }
+pub const DEVICE_PATH: &str = "/dev/input/event3";
+pub const EMERGENCY_EXIT: &str = "Emergency exit keys pressed";
+/// Exit status after the emergency keys, the unit doesn't restart on it
+pub const EMERGENCY_EXIT_STATUS: i32 = 3;
+
+/// The BTN_* codes of mice, joysticks and the like, keys are the rest
+fn is_button(key: Key) -> bool {
+    matches!(key.code(), 0x100..0x160 | 0x2c0..0x300)
+}
+
+/// Key or button press or release carried by a raw event, autorepeats are
+/// skipped. Pointer motion and wheel clicks come out as presses.
+pub fn to_key_event(event: &InputEvent) -> Option<Event> {
+    match event.kind() {
+        InputEventKind::Key(key) => {
+            if event.value() != 0 && event.value() != 1 {
+                return None;
+            }
+            let action = if event.value() == 0 {
+                Action::Release
             } else {
+                Action::Press
+            };
+            // Unknown keys fall back to A in the key table, buttons go first
+            // and the ones the pointer can't send are left out
+            let key = match evdev_to_uinput_button!(key) {
+                Some(button) => button.into(),
+                None if is_button(key) => return None,
+                None => {
+                    let uinput_key: UKey = evdev_to_uinput_key!(key);
+                    uinput_key.into()
+                }
+            };
+            Some(Event { key, action })
+        }
+        InputEventKind::RelAxis(axis) => {
+            let value = event.value();
+            let key = match axis {
+                _ if value == 0 => return None,
+                RelativeAxisType::REL_X => key_buffer::Key::Move(Axis::X, value),
+                RelativeAxisType::REL_Y => key_buffer::Key::Move(Axis::Y, value),
+                RelativeAxisType::REL_WHEEL => key_buffer::Key::Scroll(Axis::Y, value),
+                RelativeAxisType::REL_HWHEEL => key_buffer::Key::Scroll(Axis::X, value),
+                // Hi-res wheel events duplicate the plain ones
+                _ => return None,
+            };
+            Some(Event {
+                key,
+                action: Action::Press,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Mice and touchpads with buttons, except our own virtual pointer
+pub fn pointer_devices() -> Vec<String> {
+    evdev::enumerate()
+        .filter(|(_, device)| device.name() != Some(POINTER_NAME))
+        .filter(|(_, device)| {
+            device
+                .supported_keys()
+                .is_some_and(|keys| keys.contains(Key::BTN_LEFT))
+                && device
+                    .supported_relative_axes()
+                    .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+        })
+        .map(|(path, _)| path.display().to_string())
+        .collect()
+}
+
+/// Feeds the source into the buffer until it runs dry or is stopped, then
+/// lets go of the device. The emergency keys are looked for before anything
+/// else, they pause remapping, ungrab the device and end the loop with an
+/// error. Once the watchdog tripped input is dropped, the system gets it
+/// straight from the ungrabbed device. SYN_REPORT ends the frame in the
+/// buffer, so the output syncs passed on events the way they came in.
+pub fn grab_kb_events(
+    source: &mut dyn InputSource,
+    buffer: Arc<KeyBuffer>,
+    mut emergency: Option<&mut EmergencyWatch>,
+    watchdog: Option<&Watchdog>,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    while let Some(event) = source.next_event()? {
+        if event.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+            if !watchdog.is_some_and(|w| w.tripped()) {
+                buffer.end_frame();
+            }
+            continue;
+        }
+        if let Some(event) = to_key_event(&event) {
+            log_trace!(
+                "Input";
+                device = source.label(),
+                key = event.key,
+                pressed = (event.action == Action::Press)
+            );
+            if let Some(watch) = emergency.as_deref_mut()
+                && watch.feed(&event, start.elapsed().as_millis() as i64)
+            {
+                buffer.pause(None);
+                source.ungrab()?;
+                return Err(EMERGENCY_EXIT.into());
+            }
+            if watchdog.is_some_and(|w| w.tripped()) {
+                continue;
             }
+            let _busy = watchdog.map(|w| w.input());
+            buffer.push_at(event.key, event.action, source.read_at());
+        }
+    }
+    source.ungrab()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::config::{ShutdownPolicy, config_from_str};
+    use crate::udev_loop::{MemorySink, OutputSink, Udev};
+    use evdev::EventType;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn rel(axis: RelativeAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::RELATIVE, axis.0, value)
+    }
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), value)
+    }
+
+    #[test]
+    fn test_to_key_event_pointer() {
+        assert_eq!(
+            to_key_event(&key(Key::BTN_SIDE, 1)),
+            Some(Event {
+                key: UButton::Side.into(),
+                action: Action::Press,
+            })
+        );
+        assert_eq!(
+            to_key_event(&rel(RelativeAxisType::REL_HWHEEL, -1)),
+            Some(Event {
+                key: key_buffer::Key::Scroll(Axis::X, -1),
+                action: Action::Press,
+            })
+        );
+        assert_eq!(
+            to_key_event(&rel(RelativeAxisType::REL_Y, 7)),
+            Some(Event {
+                key: key_buffer::Key::Move(Axis::Y, 7),
+                action: Action::Press,
+            })
+        );
+        // No such button on the pointer, and not an A either
+        assert_eq!(to_key_event(&key(Key::BTN_TOUCH, 1)), None);
+        assert_eq!(to_key_event(&key(Key::BTN_TRIGGER_HAPPY1, 1)), None);
+        assert_eq!(to_key_event(&rel(RelativeAxisType::REL_WHEEL_HI_RES, 120)), None);
+        assert_eq!(to_key_event(&rel(RelativeAxisType::REL_X, 0)), None);
+    }
+
+    #[test]
+    fn test_pipeline_pointer_triggers() {
+        let config = config_from_str(
+            r#"
+            [main]
+            "leftctrl + mouse_side" = "f13"
+            "scroll right" = "f14"
+            "#,
+        );
+        assert!(config.uses_pointer());
+        let clock = VirtualClock::new();
+        let buffer = KeyBuffer::with_clock(config, clock.clone()).unwrap();
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        Udev::start_listen(sink.clone(), buffer.clone(), None);
+
+        let mut source = MemorySource::new(vec![
+            rel(RelativeAxisType::REL_X, 3),
+            key(Key::KEY_LEFTCTRL, 1),
+            key(Key::BTN_SIDE, 1),
+            key(Key::BTN_SIDE, 0),
+            key(Key::KEY_LEFTCTRL, 0),
+            rel(RelativeAxisType::REL_HWHEEL, 1),
+            key(Key::BTN_EXTRA, 1),
+            key(Key::BTN_EXTRA, 0),
+        ]);
+        grab_kb_events(&mut source, buffer.clone(), None, None).unwrap();
+        clock.advance(1000);
+
+        let press = |key: key_buffer::Key| Event {
+            key,
+            action: Action::Press,
+        };
+        let release = |key: key_buffer::Key| Event {
+            key,
+            action: Action::Release,
+        };
+        let expected = vec![
+            press(key_buffer::Key::Move(Axis::X, 3)),
+            press(UButton::Extra.into()),
+            release(UButton::Extra.into()),
+            press(UKey::F13.into()),
+            release(UKey::F13.into()),
+            press(UKey::F14.into()),
+            release(UKey::F14.into()),
+        ];
+        let start = Instant::now();
+        while sink.lock().unwrap().events.len() < expected.len()
+            && start.elapsed() < Duration::from_secs(1)
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(sink.lock().unwrap().events, expected);
+    }
+
+    #[test]
+    fn test_pipeline_frames() {
+        let config = config_from_str(
+            r#"
+            [main]
+            "scroll right" = "f14"
+            "#,
+        );
+        let buffer = KeyBuffer::with_clock(config, VirtualClock::new()).unwrap();
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        let output = Udev::start_listen(sink.clone(), buffer.clone(), None);
+        let syn = InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0);
+
+        let mut source = MemorySource::new(vec![
+            rel(RelativeAxisType::REL_X, 3),
+            rel(RelativeAxisType::REL_Y, -2),
+            syn,
+            rel(RelativeAxisType::REL_X, 1),
+            syn,
+        ]);
+        grab_kb_events(&mut source, buffer.clone(), None, None).unwrap();
+        buffer.shutdown(ShutdownPolicy::Cancel);
+        output.join().unwrap();
+
+        let sink = sink.lock().unwrap();
+        assert_eq!(sink.events.len(), 3);
+        // One sync per frame, not per event, and the last one on shutdown
+        assert_eq!(sink.syncs, 3);
+    }
+
+    #[test]
+    fn test_pipeline_headless() {
+        let config = config_from_str(
+            r#"
+            [main]
+            "leftmeta + leftshift + F23" = "leftctrl down + wait 300 + leftctrl up"
+            "#,
+        );
+        let clock = VirtualClock::new();
+        let buffer = KeyBuffer::with_clock(config, clock.clone()).unwrap();
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        Udev::start_listen(sink.clone(), buffer.clone(), None);
+
+        let mut source = MemorySource::from_keys(&[
+            (Key::KEY_X, 1),
+            (Key::KEY_X, 2),
+            (Key::KEY_X, 0),
+            (Key::KEY_LEFTMETA, 1),
+            (Key::KEY_LEFTSHIFT, 1),
+            (Key::KEY_F23, 1),
+            (Key::KEY_F23, 0),
+            (Key::KEY_LEFTSHIFT, 0),
+            (Key::KEY_LEFTMETA, 0),
+        ]);
+        grab_kb_events(&mut source, buffer.clone(), None, None).unwrap();
+        clock.advance(1000);
+
+        let expected = vec![
+            Event {
+                key: UKey::X.into(),
+                action: Action::Press,
+            },
+            Event {
+                key: UKey::X.into(),
+                action: Action::Release,
+            },
+            Event {
+                key: UKey::LeftControl.into(),
+                action: Action::Press,
+            },
+            Event {
+                key: UKey::LeftControl.into(),
+                action: Action::Release,
+            },
+        ];
+        let start = Instant::now();
+        while sink.lock().unwrap().events.len() < expected.len()
+            && start.elapsed() < Duration::from_secs(1)
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let sink = sink.lock().unwrap();
+        assert_eq!(sink.events, expected);
+        assert_eq!(sink.syncs, expected.len());
+        // X both ways, then the first key of the macro
+        let metrics = buffer.metrics().render();
+        assert!(metrics.contains(r#"kbd_latency_seconds_count{path="passthrough"} 2"#));
+        assert!(metrics.contains(r#"kbd_latency_seconds_count{path="remapped"} 1"#));
+    }
+
+    #[test]
+    fn test_pipeline_emergency_exit() {
+        let config = config_from_str(
+            r#"
+            [main]
+            "x" = "y"
+            "#,
+        );
+        let mut emergency = EmergencyWatch::new(config.emergency_exit.clone());
+        let buffer = KeyBuffer::with_clock(config, VirtualClock::new()).unwrap();
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        Udev::start_listen(sink.clone(), buffer.clone(), None);
+
+        let mut source = MemorySource::from_keys(&[
+            (Key::KEY_LEFTSHIFT, 1),
+            (Key::KEY_BACKSPACE, 1),
+            (Key::KEY_ESC, 1),
+            (Key::KEY_ESC, 0),
+            (Key::KEY_ESC, 1),
+            (Key::KEY_ESC, 0),
+            (Key::KEY_ESC, 1),
+            (Key::KEY_Z, 1),
+        ]);
+        let result = grab_kb_events(&mut source, buffer.clone(), Some(&mut emergency), None);
+        assert_eq!(result.unwrap_err().to_string(), EMERGENCY_EXIT);
+        assert!(buffer.is_paused());
+        // Nothing after the keys is read, not even the end of their frame
+        let next = source.next_event().unwrap().unwrap();
+        assert_eq!(
+            next.kind(),
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT)
+        );
+        let next = source.next_event().unwrap().unwrap();
+        assert_eq!(next.kind(), InputEventKind::Key(Key::KEY_Z));
+
+        let start = Instant::now();
+        while sink.lock().unwrap().events.len() < 6 && start.elapsed() < Duration::from_secs(1) {
+            std::thread::sleep(Duration::from_millis(1));
         }
+        let mut sink = sink.lock().unwrap();
+        sink.release_all().unwrap();
+        let mut released: Vec<_> = sink.events[6..].iter().map(|e| e.key).collect();
+        released.sort_by_key(|k| k.to_string());
+        assert_eq!(
+            released,
+            vec![UKey::BackSpace.into(), UKey::LeftShift.into()]
+        );
+        assert!(sink.events[6..].iter().all(|e| e.action == Action::Release));
     }
 }
@@ -1,7 +1,7 @@
-pub const MAX_ID: u8 = u8::MAX;
+/// Hands out ids for scheduled events. 64 bits never wrap in practice, so
+/// an id can't collide with one that is still in use.
 pub struct IdGenerator {
-    current: u8,
-    
+    current: u64,
 }
 
 impl IdGenerator {
@@ -11,7 +11,7 @@ impl IdGenerator {
 }
 
 impl Iterator for IdGenerator {
-    type Item = u8;
+    type Item = u64;
 
     fn next(&mut self) -> Option<Self::Item> {
         let id = self.current;
@@ -39,9 +39,16 @@ mod tests {
     }
 
     #[test]
-    fn test_id_generator_wraps_around() {
-        let mut gn = IdGenerator { current: 255};
+    fn test_id_generator_past_u8() {
+        let mut gn = IdGenerator { current: 255 };
         assert_eq!(gn.next(), Some(255));
+        assert_eq!(gn.next(), Some(256));
+    }
+
+    #[test]
+    fn test_id_generator_wraps_around() {
+        let mut gn = IdGenerator { current: u64::MAX };
+        assert_eq!(gn.next(), Some(u64::MAX));
         assert_eq!(gn.next(), Some(0));
         assert_eq!(gn.next(), Some(1));
     }
@@ -49,7 +56,7 @@ mod tests {
     #[test]
     fn test_id_generator_multiple_iterations() {
         let mut gn = IdGenerator::new();
-        let ids: Vec<u8> = gn.by_ref().take(5).collect();
+        let ids: Vec<u64> = gn.by_ref().take(5).collect();
         assert_eq!(ids, vec![0, 1, 2, 3, 4]);
     }
 }
@@ -1,120 +1,366 @@
-use std::collections::HashMap;
+#![allow(dead_code)]
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 
-use crate::key_buffer::{Event, SafeSender};
-use id_generator::{IdGenerator, MAX_ID};
+use crate::clock::{ClockGuard, SafeClock, SystemClock};
+use crate::config::Output;
+use crate::exec::{self, ExecCommand};
+use crate::log_error;
+use crate::key_buffer::{Action, Event, Key, SafeSender};
+use crate::metrics::{Path, Stamp};
+use id_generator::IdGenerator;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 mod id_generator;
+
+pub type MacroId = u64;
+pub type ExecRunner = Arc<dyn Fn(&ExecCommand) + Send + Sync>;
+
+/// Events of one fired action that are not sent yet
+#[derive(Default)]
+struct Macro {
+    pending: HashSet<u64>,
+    // Keys pressed by the macro and not released so far, in press order
+    held: Vec<Key>,
+    // Set for macros that run again and again until cancelled
+    repeat: Option<ClockGuard>,
+    // When the input that fired it was read, given to the first key sent
+    read_at: Option<Instant>,
+}
+
+// Every field is shared, clones schedule into the same queue
+#[derive(Clone)]
 pub struct KeyScheduler {
-    timer: timer::Timer,
+    clock: SafeClock,
     sender: SafeSender,
-    guards: Arc<Mutex<HashMap<u8, timer::Guard>>>,
+    guards: Arc<Mutex<HashMap<u64, ClockGuard>>>,
+    macros: Arc<Mutex<HashMap<MacroId, Macro>>>,
     id_generator: Arc<Mutex<IdGenerator>>,
+    // Limit for pending presses, unbounded when not set
+    capacity: Option<usize>,
+    exec: ExecRunner,
 }
 
 impl KeyScheduler {
     pub fn new(sender: SafeSender) -> Result<Self, Box<dyn Error>> {
+        KeyScheduler::with_clock(sender, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(sender: SafeSender, clock: SafeClock) -> Result<Self, Box<dyn Error>> {
         Ok(KeyScheduler {
-            timer: timer::Timer::new(),
-            sender: sender,
-            guards: Arc::new(Mutex::new(HashMap::<u8, timer::Guard>::with_capacity(
-                MAX_ID as usize,
-            ))),
+            clock,
+            sender,
+            guards: Arc::new(Mutex::new(HashMap::<u64, ClockGuard>::new())),
+            macros: Arc::new(Mutex::new(HashMap::<MacroId, Macro>::new())),
             id_generator: Arc::new(Mutex::new(IdGenerator::new())),
+            capacity: None,
+            exec: Arc::new(exec::spawn_logged),
         })
     }
 
+    /// Replaces what runs the commands of `exec` actions, spawning a process
+    /// by default
+    pub fn set_exec_runner(&mut self, exec: ExecRunner) {
+        self.exec = exec;
+    }
+
+    /// Caps the number of pending events. Once reached new presses are
+    /// rejected, releases are always scheduled so no key is left held down.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.guards.lock().unwrap().len()
+    }
+
     pub fn schedule(&mut self, event: Event, delay_ms: i64) -> Result<(), Box<dyn Error>> {
-        {
-            let guards = self.guards.lock().unwrap();
-            if guards.len() >= MAX_ID as usize {
-                // Too many events scheduled
-                return Err("Maximum number of scheduled events reached".into());
+        self._schedule(Output::Key(event), delay_ms, None)
+            .map(|_| ())
+    }
+
+    /// Schedules the events of an action as one macro, the returned id can
+    /// be used to cancel what is not sent yet
+    pub fn schedule_macro(
+        &mut self,
+        events: Vec<(i64, Output)>,
+        read_at: Option<Instant>,
+    ) -> MacroId {
+        let macro_id = self.id_generator.lock().unwrap().next().unwrap();
+        // Callbacks of the macro wait here until it is registered
+        let macros = self.macros.clone();
+        let mut locked_macros = macros.lock().unwrap();
+        let mut m = Macro {
+            read_at,
+            ..Macro::default()
+        };
+        for (delay, event) in events {
+            match self._schedule(event, delay, Some(macro_id)) {
+                Ok(id) => {
+                    m.pending.insert(id);
+                }
+                Err(e) => log_error!("Error scheduling event: {e}"),
             }
         }
+        if !m.pending.is_empty() {
+            locked_macros.insert(macro_id, m);
+        }
+        macro_id
+    }
+
+    /// Runs the events right away and then every `interval_ms` until the
+    /// macro is cancelled
+    pub fn schedule_repeating(
+        &mut self,
+        events: Vec<(i64, Output)>,
+        interval_ms: i64,
+        read_at: Option<Instant>,
+    ) -> MacroId {
+        let macro_id = self.schedule_macro(events.clone(), read_at);
+        let mut ks = self.clone();
+        let repeat = self.clock.schedule_repeating(
+            chrono::Duration::milliseconds(interval_ms),
+            Box::new(move || {
+                let macros = ks.macros.clone();
+                let mut locked_macros = macros.lock().unwrap();
+                if !locked_macros.contains_key(&macro_id) {
+                    return;
+                }
+                for (delay, event) in &events {
+                    match ks._schedule(event.clone(), *delay, Some(macro_id)) {
+                        Ok(id) => {
+                            if let Some(m) = locked_macros.get_mut(&macro_id) {
+                                m.pending.insert(id);
+                            }
+                        }
+                        Err(e) => log_error!("Error scheduling event: {e}"),
+                    }
+                }
+            }),
+        );
+        self.macros
+            .lock()
+            .unwrap()
+            .entry(macro_id)
+            .or_default()
+            .repeat = Some(repeat);
+        macro_id
+    }
+
+    pub fn is_running(&self, macro_id: MacroId) -> bool {
+        self.macros.lock().unwrap().contains_key(&macro_id)
+    }
+
+    /// Drops the pending events of a macro and releases the keys it holds.
+    /// Returns false if the macro has already finished.
+    pub fn cancel(&mut self, macro_id: MacroId) -> bool {
+        let mut macros = self.macros.lock().unwrap();
+        let Some(m) = macros.remove(&macro_id) else {
+            return false;
+        };
+        let mut guards = self.guards.lock().unwrap();
+        for id in &m.pending {
+            guards.remove(id);
+        }
+        let sender = self.sender.lock().unwrap();
+        for key in m.held.into_iter().rev() {
+            sender
+                .send(Event {
+                    key,
+                    action: Action::Release,
+                })
+                .unwrap();
+        }
+        true
+    }
+
+    fn _schedule(
+        &mut self,
+        output: Output,
+        delay_ms: i64,
+        macro_id: Option<MacroId>,
+    ) -> Result<u64, Box<dyn Error>> {
+        // Held until the guard is stored, so a callback firing right away
+        // can't try to remove it before it's inserted
+        let mut locked_guards = self.guards.lock().unwrap();
+        if let Some(capacity) = self.capacity
+            && locked_guards.len() >= capacity
+            && !matches!(&output, Output::Key(e) if e.action == Action::Release)
+        {
+            // Too many events scheduled
+            return Err(format!("Maximum of {capacity} scheduled events reached, dropped {output:?}").into());
+        }
 
         let s = self.sender.clone();
+        let exec = self.exec.clone();
         let guards = self.guards.clone();
+        let macros = self.macros.clone();
         let id = self.id_generator.lock().unwrap().next().unwrap();
-        let g =
-            self.timer
-                .schedule_with_delay(chrono::Duration::milliseconds(delay_ms), move || {
-                    s.lock().unwrap().send(event.clone()).unwrap();
-                    guards.lock().unwrap().remove(&id);
-                });
-        self.guards.lock().unwrap().insert(id, g);
-        Ok(())
+        let g = self.clock.schedule_with_delay(
+            chrono::Duration::milliseconds(delay_ms),
+            Box::new(move || {
+                // Macros are locked first everywhere, then guards
+                let mut macros = macros.lock().unwrap();
+                let mut stamp = None;
+                if let Some(macro_id) = macro_id {
+                    // Cancelled while the timer was already firing
+                    let Some(m) = macros.get_mut(&macro_id) else {
+                        return;
+                    };
+                    m.pending.remove(&id);
+                    if let Output::Key(event) = &output
+                        && !event.key.is_relative()
+                    {
+                        match event.action {
+                            Action::Press if !m.held.contains(&event.key) => m.held.push(event.key),
+                            Action::Press => {}
+                            Action::Release => m.held.retain(|k| *k != event.key),
+                        }
+                    }
+                    if matches!(output, Output::Key(_)) {
+                        stamp = m.read_at.take().map(|read_at| Stamp {
+                            read_at,
+                            path: Path::Remapped,
+                        });
+                    }
+                    if m.pending.is_empty() && m.repeat.is_none() {
+                        macros.remove(&macro_id);
+                    }
+                }
+                match &output {
+                    Output::Key(event) => {
+                        // Taken before the macros are let go, so a cancel
+                        // can't send its release ahead of this press
+                        let s = s.lock().unwrap();
+                        drop(macros);
+                        match stamp {
+                            Some(stamp) => s.send_stamped(event.clone(), stamp),
+                            None => s.send(event.clone()),
+                        }
+                        .unwrap()
+                    }
+                    // Forks and looks up the user, nothing stays locked
+                    Output::Exec(command) => {
+                        drop(macros);
+                        exec(command)
+                    }
+                }
+                guards.lock().unwrap().remove(&id);
+            }),
+        );
+        locked_guards.insert(id, g);
+        Ok(id)
+    }
+
+    /// Drops every event not sent yet, keys held by macros are released
+    pub fn clear(&mut self) {
+        let ids: Vec<MacroId> = self.macros.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.cancel(id);
+        }
+        self.guards.lock().unwrap().clear();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::VirtualClock;
     use crate::key_buffer::{Action, Event, UKey};
-    use std::sync::mpsc::channel;
+    use std::sync::mpsc::{Receiver, channel};
     use std::sync::{Arc, Mutex};
 
-    #[test]
-    fn test_scheduler() {
+    fn virtual_scheduler() -> (KeyScheduler, Receiver<Event>, Arc<VirtualClock>) {
         let (tx, rx) = channel::<Event>();
         let tx = Arc::new(Mutex::new(tx));
+        let clock = VirtualClock::new();
+        let ks = KeyScheduler::with_clock(tx, clock.clone()).unwrap();
+        (ks, rx, clock)
+    }
 
-        let mut ks = KeyScheduler::new(tx).unwrap();
+    #[test]
+    fn test_scheduler() {
+        let (mut ks, rx, clock) = virtual_scheduler();
         ks.schedule(
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Release,
             },
             300,
         ).unwrap();
         ks.schedule(
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             30,
         ).unwrap();
 
-        std::thread::sleep(std::time::Duration::from_millis(400));
-
+        clock.advance(29);
+        assert!(rx.try_recv().is_err());
+        clock.advance(1);
         let received = rx.try_recv().unwrap();
         assert_eq!(
             received,
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             }
         );
+        clock.advance(269);
+        assert!(rx.try_recv().is_err());
+        clock.advance(1);
         let received = rx.try_recv().unwrap();
         assert_eq!(
             received,
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Release,
             }
         );
     }
+
     #[test]
-    fn test_scheduler_zero_delay() {
+    fn test_scheduler_system_clock() {
         let (tx, rx) = channel::<Event>();
         let tx = Arc::new(Mutex::new(tx));
 
         let mut ks = KeyScheduler::new(tx).unwrap();
         ks.schedule(
             Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::A),
+                action: Action::Press,
+            },
+            5,
+        ).unwrap();
+        let received = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            received,
+            Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Press,
+            }
+        );
+    }
+
+    #[test]
+    fn test_scheduler_zero_delay() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        ks.schedule(
+            Event {
+                key: Key::Keyboard(UKey::B),
                 action: Action::Press,
             },
             0,
         ).unwrap();
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        clock.advance(0);
         let received = rx.try_recv().unwrap();
         assert_eq!(
             received,
             Event {
-                key: UKey::B,
+                key: Key::Keyboard(UKey::B),
                 action: Action::Press,
             }
         );
@@ -122,24 +368,21 @@ mod tests {
 
     #[test]
     fn test_scheduler_negative_delay() {
-        let (tx, rx) = channel::<Event>();
-        let tx = Arc::new(Mutex::new(tx));
-
-        let mut ks = KeyScheduler::new(tx).unwrap();
+        let (mut ks, rx, clock) = virtual_scheduler();
         ks.schedule(
             Event {
-                key: UKey::C,
+                key: Key::Keyboard(UKey::C),
                 action: Action::Release,
             },
             -100,
         ).unwrap();
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        clock.advance(0);
         let received = rx.try_recv().unwrap();
         assert_eq!(
             received,
             Event {
-                key: UKey::C,
+                key: Key::Keyboard(UKey::C),
                 action: Action::Release,
             }
         );
@@ -147,14 +390,11 @@ mod tests {
 
     #[test]
     fn test_scheduler_multiple_events_same_key() {
-        let (tx, rx) = channel::<Event>();
-        let tx = Arc::new(Mutex::new(tx));
-
-        let mut ks = KeyScheduler::new(tx).unwrap();
+        let (mut ks, rx, clock) = virtual_scheduler();
         for i in 0..5 {
             ks.schedule(
                 Event {
-                    key: UKey::D,
+                    key: Key::Keyboard(UKey::D),
                     action: if i % 2 == 0 {
                         Action::Press
                     } else {
@@ -165,7 +405,7 @@ mod tests {
             ).unwrap();
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(60));
+        clock.advance(40);
         let mut results = vec![];
         while let Ok(event) = rx.try_recv() {
             results.push(event);
@@ -174,14 +414,14 @@ mod tests {
         assert_eq!(
             results[0],
             Event {
-                key: UKey::D,
+                key: Key::Keyboard(UKey::D),
                 action: Action::Press,
             }
         );
         assert_eq!(
             results[1],
             Event {
-                key: UKey::D,
+                key: Key::Keyboard(UKey::D),
                 action: Action::Release,
             }
         );
@@ -189,22 +429,20 @@ mod tests {
 
     #[test]
     fn test_no_event_sent_if_not_scheduled() {
-        let (_tx, rx) = channel::<Event>();
-        std::thread::sleep(std::time::Duration::from_millis(50));
+        let (_ks, rx, clock) = virtual_scheduler();
+        clock.advance(50);
         assert!(rx.try_recv().is_err());
     }
 
     #[test]
     fn test_schedule_many_events() {
-        let (tx, rx) = channel::<Event>();
-        let tx = Arc::new(Mutex::new(tx));
-        let mut ks = KeyScheduler::new(tx).unwrap();
+        let (mut ks, rx, clock) = virtual_scheduler();
         const EVENTS: i64 = 200;
 
         for i in 0..EVENTS {
             ks.schedule(
                 Event {
-                    key: UKey::A,
+                    key: Key::Keyboard(UKey::A),
                     action: if i % 2 == 0 {
                         Action::Press
                     } else {
@@ -216,22 +454,22 @@ mod tests {
             .unwrap();
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(300));
+        clock.advance(EVENTS);
         let mut count = 0;
-        while let Ok(_) = rx.try_recv() {
+        while rx.try_recv().is_ok() {
             count += 1;
         }
         assert_eq!(count, EVENTS);
+        assert!(ks.guards.lock().unwrap().is_empty());
     }
     #[test]
-    fn test_schedule_too_many_events() {
-        let (tx, rx) = channel::<Event>();
-        let tx = Arc::new(Mutex::new(tx));
-        let mut ks = KeyScheduler::new(tx).unwrap();
-        for i in 0..MAX_ID {
+    fn test_schedule_unbounded() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        const EVENTS: usize = 1000;
+        for i in 0..EVENTS {
             ks.schedule(
                 Event {
-                    key: UKey::A,
+                    key: Key::Keyboard(UKey::A),
                     action: if i % 2 == 0 {
                         Action::Press
                     } else {
@@ -242,20 +480,225 @@ mod tests {
             )
             .unwrap();
         }
+        assert_eq!(ks.pending(), EVENTS);
+
+        clock.advance(50);
+        let mut count = 0;
+        while rx.try_recv().is_ok() {
+            count += 1;
+        }
+        assert_eq!(count, EVENTS);
+        assert_eq!(ks.pending(), 0);
+    }
+
+    #[test]
+    fn test_schedule_too_many_events() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        const CAPACITY: usize = 255;
+        ks.set_capacity(Some(CAPACITY));
+        for _ in 0..CAPACITY {
+            ks.schedule(
+                Event {
+                    key: Key::Keyboard(UKey::A),
+                    action: Action::Press,
+                },
+                50,
+            )
+            .unwrap();
+        }
         let result = ks.schedule(
             Event {
-                key: UKey::A,
+                key: Key::Keyboard(UKey::A),
                 action: Action::Press,
             },
             0,
         );
         assert!(result.is_err());
+        // Releases are never shed
+        ks.schedule(
+            Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Release,
+            },
+            60,
+        )
+        .unwrap();
 
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        let mut count = 0;
-        while let Ok(_) = rx.try_recv() {
-            count += 1;
+        clock.advance(60);
+        let mut received = vec![];
+        while let Ok(event) = rx.try_recv() {
+            received.push(event);
         }
-        assert_eq!(count, MAX_ID);
+        assert_eq!(received.len(), CAPACITY + 1);
+        assert_eq!(
+            received.last(),
+            Some(&Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Release,
+            })
+        );
+    }
+
+    fn macro_events() -> Vec<(i64, Output)> {
+        vec![
+            (
+                0,
+                Output::Key(Event {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Action::Press,
+                }),
+            ),
+            (
+                300,
+                Output::Key(Event {
+                    key: Key::Keyboard(UKey::LeftControl),
+                    action: Action::Release,
+                }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_macro_finishes() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        let id = ks.schedule_macro(macro_events(), None);
+        assert!(ks.is_running(id));
+        clock.advance(300);
+        assert_eq!(rx.try_iter().count(), 2);
+        assert!(!ks.is_running(id));
+        assert!(!ks.cancel(id));
+    }
+
+    #[test]
+    fn test_macro_cancel_releases_held() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        let id = ks.schedule_macro(macro_events(), None);
+        clock.advance(100);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event {
+                key: Key::Keyboard(UKey::LeftControl),
+                action: Action::Press,
+            }
+        );
+        assert!(ks.cancel(id));
+        // Release is sent right away instead of at 300
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event {
+                key: Key::Keyboard(UKey::LeftControl),
+                action: Action::Release,
+            }
+        );
+        clock.advance(500);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(ks.pending(), 0);
+
+        // Nothing pressed yet, nothing to release
+        let id = ks.schedule_macro(
+            macro_events()
+                .into_iter()
+                .map(|(delay, event)| (delay + 50, event))
+                .collect(),
+            None,
+        );
+        assert!(ks.cancel(id));
+        clock.advance(500);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_macro_repeating() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        let tap = vec![
+            (
+                0,
+                Output::Key(Event {
+                    key: Key::Keyboard(UKey::A),
+                    action: Action::Press,
+                }),
+            ),
+            (
+                10,
+                Output::Key(Event {
+                    key: Key::Keyboard(UKey::A),
+                    action: Action::Release,
+                }),
+            ),
+        ];
+        let id = ks.schedule_repeating(tap, 50, None);
+        clock.advance(0);
+        assert_eq!(rx.try_iter().count(), 1);
+        clock.advance(120);
+        // Released at 10, again at 50 and 60, 100 and 110
+        assert_eq!(rx.try_iter().count(), 5);
+        assert!(ks.is_running(id));
+
+        clock.advance(35);
+        assert_eq!(rx.try_iter().count(), 1);
+        // Held "a" goes up when the repeat stops
+        assert!(ks.cancel(id));
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event {
+                key: Key::Keyboard(UKey::A),
+                action: Action::Release,
+            }
+        );
+        clock.advance(500);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(ks.pending(), 0);
+        assert_eq!(clock.pending(), 0);
+    }
+
+    #[test]
+    fn test_macro_exec() {
+        let (mut ks, rx, clock) = virtual_scheduler();
+        let ran = Arc::new(Mutex::new(Vec::<String>::new()));
+        let r = ran.clone();
+        ks.set_exec_runner(Arc::new(move |c: &ExecCommand| {
+            r.lock().unwrap().push(c.command.clone())
+        }));
+        let exec = |command: &str| {
+            Output::Exec(ExecCommand {
+                command: command.to_string(),
+                user: "alice".to_string(),
+                env: vec![],
+            })
+        };
+        ks.schedule_macro(vec![(0, exec("first")), (100, exec("second"))], None);
+        clock.advance(50);
+        assert_eq!(*ran.lock().unwrap(), vec!["first"]);
+        clock.advance(50);
+        assert_eq!(*ran.lock().unwrap(), vec!["first", "second"]);
+        // Nothing goes to the keyboard
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_macro_exec_unlocked() {
+        let (mut ks, _rx, clock) = virtual_scheduler();
+        let probe = ks.clone();
+        let running = Arc::new(Mutex::new(None));
+        let r = running.clone();
+        // Would deadlock if the macros were still locked
+        ks.set_exec_runner(Arc::new(move |_: &ExecCommand| {
+            *r.lock().unwrap() = Some(probe.is_running(0));
+        }));
+        let id = ks.schedule_macro(
+            vec![(
+                0,
+                Output::Exec(ExecCommand {
+                    command: "true".to_string(),
+                    user: "alice".to_string(),
+                    env: vec![],
+                }),
+            )],
+            None,
+        );
+        assert_eq!(id, 0);
+        clock.advance(0);
+        // Finished once its last output was taken
+        assert_eq!(*running.lock().unwrap(), Some(false));
     }
 }
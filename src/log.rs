@@ -0,0 +1,293 @@
+use std::fmt::{self, Display};
+#[cfg(not(test))]
+use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Filter used when neither `KBD_LOG` nor the config set one
+#[cfg(debug_assertions)]
+pub const DEFAULT_FILTER: &str = "debug";
+#[cfg(not(debug_assertions))]
+pub const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Result<Level, String> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "off" => Level::Off,
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            other => return Err(format!("Unknown log level {other}")),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// syslog priority, journald reads it from a `<N>` line prefix
+    fn priority(self) -> u8 {
+        match self {
+            Level::Off | Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+/// Level for everything plus levels for targets, the most specific
+/// target wins. A target is a module path without the crate name, e.g.
+/// `key_buffer` or `config::parser`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    default: Level,
+    targets: Vec<(String, Level)>,
+}
+
+impl Filter {
+    /// "info", "warn,key_buffer=trace", ...
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: Level::Info,
+            targets: Vec::new(),
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((target, level)) => filter
+                    .targets
+                    .push((target.trim().to_string(), Level::parse(level)?)),
+                None => filter.default = Level::parse(part)?,
+            }
+        }
+        // Longest first, so the first match is the most specific
+        filter
+            .targets
+            .sort_by_key(|(t, _)| std::cmp::Reverse(t.len()));
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> Level {
+        self.targets
+            .iter()
+            .find(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> Level {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Level::max)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.name())?;
+        let mut targets = self.targets.clone();
+        targets.sort();
+        for (target, level) in targets {
+            write!(f, ",{target}={}", level.name())?;
+        }
+        Ok(())
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: Level::Info,
+    targets: Vec::new(),
+});
+// Most verbose level of the filter, most calls stop here
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_filter(spec: &str) -> Result<(), String> {
+    let filter = Filter::parse(spec)?;
+    let mut current = FILTER.write().unwrap();
+    *current = filter;
+    // Under the same lock, so two calls can't leave the max of the other
+    MAX_LEVEL.store(current.max() as u8, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn filter() -> String {
+    FILTER.read().unwrap().to_string()
+}
+
+/// Sets the filter at start from `KBD_LOG`, which wins over the config
+pub fn init() {
+    let env = std::env::var("KBD_LOG").ok();
+    let spec = env.as_deref().unwrap_or(DEFAULT_FILTER);
+    if let Err(e) = set_filter(spec) {
+        set_filter(DEFAULT_FILTER).unwrap();
+        crate::log_warn!("Bad log filter {spec:?}, {e}");
+    }
+}
+
+/// `kbd::key_buffer` -> `key_buffer`, the crate root is `main`
+fn target(module: &str) -> &str {
+    match module.split_once("::") {
+        Some((_, rest)) => rest,
+        None => "main",
+    }
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && level <= FILTER.read().unwrap().level(target(module))
+}
+
+fn journald() -> bool {
+    static JOURNALD: OnceLock<bool> = OnceLock::new();
+    *JOURNALD.get_or_init(|| std::env::var_os("JOURNAL_STREAM").is_some())
+}
+
+/// `key=value`, quoted when the value has spaces, quotes or is empty
+fn write_field(out: &mut String, key: &str, value: &dyn Display) {
+    let value = value.to_string();
+    if value.is_empty() || value.contains([' ', '"', '=', '\n']) {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+        out.push_str(&format!(" {key}=\"{}\"", escaped.replace('\n', "\\n")));
+    } else {
+        out.push_str(&format!(" {key}={value}"));
+    }
+}
+
+fn format_line(
+    journald: bool,
+    level: Level,
+    module: &str,
+    message: fmt::Arguments,
+    fields: &[(&str, &dyn Display)],
+) -> String {
+    let mut line = if journald {
+        format!("<{}>", level.priority())
+    } else {
+        format!(
+            "{} {:<5} ",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            level.name().to_uppercase()
+        )
+    };
+    line.push_str(&format!("{}: {message}", target(module)));
+    for (key, value) in fields {
+        write_field(&mut line, key, *value);
+    }
+    line
+}
+
+pub fn write(level: Level, module: &str, message: fmt::Arguments, fields: &[(&str, &dyn Display)]) {
+    let line = format_line(journald(), level, module, message, fields);
+    // Tests capture what goes through eprintln only
+    #[cfg(test)]
+    eprintln!("{line}");
+    #[cfg(not(test))]
+    let _ = writeln!(std::io::stderr().lock(), "{line}");
+}
+
+/// `log_at!(level, "format", args; key = value, ...)`, the fields are
+/// optional
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(; $($key:ident = $value:expr),+)?) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::write(
+                $level,
+                module_path!(),
+                format_args!($fmt $(, $arg)*),
+                &[$($((stringify!($key), &$value as &dyn ::std::fmt::Display)),+)?],
+            );
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter =
+            Filter::parse("warn, key_buffer=trace,config=debug,config::parser=off").unwrap();
+        assert_eq!(filter.level("main"), Level::Warn);
+        assert_eq!(filter.level("key_buffer"), Level::Trace);
+        assert_eq!(filter.level("key_buffer_x"), Level::Warn);
+        assert_eq!(filter.level("config::matcher"), Level::Debug);
+        assert_eq!(filter.level("config::parser"), Level::Off);
+        assert_eq!(filter.max(), Level::Trace);
+        assert_eq!(
+            filter.to_string(),
+            "warn,config=debug,config::parser=off,key_buffer=trace"
+        );
+        assert_eq!(Filter::parse("").unwrap().to_string(), "info");
+        assert!(Filter::parse("loud").is_err());
+        assert!(Filter::parse("key_buffer=loud").is_err());
+    }
+
+    #[test]
+    fn test_format_line() {
+        let binding = "leftctrl + a";
+        let line = format_line(
+            true,
+            Level::Info,
+            "kbd::key_buffer",
+            format_args!("Matched {}", 1),
+            &[("binding", &binding), ("latency_ms", &3), ("layer", &"")],
+        );
+        assert_eq!(
+            line,
+            r#"<6>key_buffer: Matched 1 binding="leftctrl + a" latency_ms=3 layer="""#
+        );
+        let line = format_line(false, Level::Warn, "kbd", format_args!("hi"), &[]);
+        assert!(line.ends_with(" WARN  main: hi"));
+    }
+}
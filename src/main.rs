@@ -1,22 +1,257 @@
 use key_buffer::KeyBuffer;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use config::load_config;
+use config::{
+    CONFIG_PATH, ParsedConfig, ShutdownPolicy, format_timeline, load_config, read_config,
+    run_config_tests, simulate,
+};
+use key_grabber::{EmergencyWatch, EvdevSource, InputSource};
+use sd_notify::Notifier;
+use udev_loop::OutputSink;
+use watchdog::Watchdog;
 
+mod clock;
 mod config;
+mod control;
+mod evemu;
+mod exec;
 mod key_buffer;
 mod key_grabber;
 mod key_scheduler;
+mod log;
+mod metrics;
+mod mouse_keys;
+mod sd_notify;
+mod signals;
 mod udev_loop;
-mod utils;
+mod watchdog;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let uloop = udev_loop::Udev::new().expect("Failed to create Udev device");
-    let config = load_config();
+// One line, main prints it with Debug
+const USAGE: &str =
+    "usage: kbd [test-config | record <file> | replay <file> [--emit]], record needs the kbd service stopped";
+
+/// `devices` are listed on the control socket, without them it isn't opened.
+/// Expects SIGTERM and SIGINT blocked, the first one stops every thread in
+/// order, a second one exits right away.
+fn remap(
+    source: &mut dyn InputSource,
+    config: ParsedConfig,
+    pointers: Vec<EvdevSource>,
+    devices: Option<Vec<String>>,
+) -> Result<(), Box<dyn Error>> {
+    if std::env::var_os("KBD_LOG").is_none()
+        && let Some(spec) = &config.log
+    {
+        log::set_filter(spec)?;
+    }
+    let uloop = Arc::new(Mutex::new(
+        udev_loop::Udev::new().expect("Failed to create Udev device"),
+    ));
+    let mut emergency = EmergencyWatch::new(config.emergency_exit.clone());
+    let watchdog = Watchdog::new(config.watchdog_ms);
     let key_buffer = KeyBuffer::new(config)?;
 
     let buffer_cntr = key_buffer.clone();
+    let notifier = Arc::new(Notifier::from_env());
+    let status = match &devices {
+        Some(devices) => format!(
+            "Remapping {} with {CONFIG_PATH}, {} bindings",
+            devices.join(", "),
+            buffer_cntr.config().bindings().count()
+        ),
+        None => "Replaying".to_string(),
+    };
+    udev_loop::release_on_panic(uloop.clone());
+    let stop = Arc::new(AtomicBool::new(false));
+    let (stopping, output) = (stop.clone(), uloop.clone());
+    signals::on_termination(move |signal| {
+        if !stopping.swap(true, Ordering::SeqCst) {
+            log_info!("Stopping"; signal = signal);
+            return;
+        }
+        // Whatever holds the output may be what keeps kbd from stopping
+        match output.try_lock() {
+            Ok(mut output) => {
+                if let Err(e) = output.release_all() {
+                    log_error!("Failed to release keys: {e}");
+                }
+            }
+            Err(_) => log_error!("Output is locked, keys it holds stay down"),
+        }
+        std::process::exit(1);
+    });
+
+    let mut threads = Vec::new();
+    if let Some(devices) = devices {
+        let group = buffer_cntr.config().socket_group.clone();
+        threads.push(
+            control::Control::new(buffer_cntr.clone(), CONFIG_PATH, devices).serve(
+                control::SOCKET_PATH,
+                group.as_deref(),
+                stop.clone(),
+            )?,
+        );
+    }
+    let output_thread = udev_loop::Udev::start_listen(
+        uloop.clone(),
+        buffer_cntr.clone(),
+        Some(watchdog.clone()),
+    );
+    // Taken before the devices move to their threads
+    let grabs: Vec<_> = std::iter::once(source.grab_handle())
+        .chain(pointers.iter().map(|p| p.grab_handle()))
+        .flatten()
+        .collect();
+    let (output, pings, stalled) = (uloop.clone(), notifier.clone(), notifier.clone());
+    // Pings stop after a stall, systemd restarts kbd
+    let on_alive = move || pings.ping();
+    threads.push(watchdog.clone().watch(stop.clone(), on_alive, move |reason| {
+        log_error!("Stalled, letting go of the keyboard"; reason = reason);
+        for grab in &grabs {
+            if let Err(e) = grab.ungrab() {
+                log_error!("Failed to ungrab: {e}");
+            }
+        }
+        // Likely held by whatever is stuck
+        match output.try_lock() {
+            Ok(mut output) => {
+                if let Err(e) = output.release_all() {
+                    log_error!("Failed to release keys: {e}");
+                }
+            }
+            Err(_) => log_error!("Output is locked, keys it holds stay down"),
+        }
+        stalled.status(&format!("Stalled, {reason}, waiting for a restart"));
+    }));
+    for mut pointer in pointers {
+        let buffer = buffer_cntr.clone();
+        let watchdog = watchdog.clone();
+        pointer.stop_on(stop.clone());
+        threads.push(std::thread::spawn(move || {
+            if let Err(e) =
+                key_grabber::grab_kb_events(&mut pointer, buffer, None, Some(&watchdog))
+            {
+                log_error!("Pointer input stopped: {e}"; device = pointer.path());
+            }
+        }));
+    }
+    source.stop_on(stop.clone());
+    notifier.ready(&status);
+    log_info!("{status}");
+    let result = key_grabber::grab_kb_events(
+        source,
+        buffer_cntr.clone(),
+        Some(&mut emergency),
+        Some(&watchdog),
+    );
+
+    // Everything else goes down with the keyboard
+    notifier.stopping();
+    stop.store(true, Ordering::SeqCst);
+    let policy = match result {
+        Ok(()) => buffer_cntr.config().on_shutdown,
+        Err(_) => ShutdownPolicy::Cancel,
+    };
+    buffer_cntr.shutdown(policy);
+    for thread in threads {
+        let _ = thread.join();
+    }
+    // Lets go of every key on its way out
+    let _ = output_thread.join();
+    result
+}
+
+/// Mice to grab: the configured ones, or all of them once a binding needs
+/// a mouse button or the wheel
+fn open_pointers(config: &ParsedConfig) -> Result<Vec<EvdevSource>, Box<dyn Error>> {
+    let paths = match &config.pointer_devices {
+        Some(paths) => paths.clone(),
+        None if config.uses_pointer() => key_grabber::pointer_devices(),
+        None => Vec::new(),
+    };
+    paths.iter().map(|path| EvdevSource::open(path)).collect()
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    signals::block_termination();
+    let config = load_config()?;
+    let pointers = open_pointers(&config)?;
+    let mut source = EvdevSource::open(key_grabber::DEVICE_PATH)?;
+    let devices = std::iter::once((key_grabber::DEVICE_PATH.to_string(), &source))
+        .chain(pointers.iter().map(|p| (p.path().to_string(), p)))
+        .map(|(path, s)| format!("{path} {}", s.device().name().unwrap_or("unknown")))
+        .collect();
+    let result = remap(&mut source, config, pointers, Some(devices));
+    if let Err(e) = &result
+        && e.to_string() == key_grabber::EMERGENCY_EXIT
+    {
+        log_warn!("{e}, exiting");
+        std::process::exit(key_grabber::EMERGENCY_EXIT_STATUS);
+    }
+    result
+}
+
+fn test_config() -> Result<(), Box<dyn Error>> {
+    let config = read_config()?;
+    if !run_config_tests(&config) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Writes events of the keyboard to an evemu file until interrupted. The
+/// keyboard isn't grabbed, so Ctrl+C still reaches the terminal, and while
+/// the daemon holds the grab nothing would be recorded.
+fn record(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut source = EvdevSource::watch(key_grabber::DEVICE_PATH)?;
+    if source.grabbed_elsewhere()? {
+        return Err(format!(
+            "{} is grabbed, likely by the running kbd service, stop it to record",
+            key_grabber::DEVICE_PATH
+        )
+        .into());
+    }
+    let device = source.device();
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut writer = evemu::EvemuWriter::new(
+        file,
+        device.name().unwrap_or("unknown"),
+        device.input_id(),
+        device.supported_keys(),
+    )?;
+    println!("Recording {} to {path}, Ctrl+C to stop", key_grabber::DEVICE_PATH);
+    while let Some(event) = source.next_event()? {
+        writer.write_event(&event)?;
+    }
+    Ok(())
+}
+
+/// Runs an evemu file through the bindings. Prints the output timeline, or
+/// with `--emit` plays it in real time on the virtual device.
+fn replay(path: &str, emit: bool) -> Result<(), Box<dyn Error>> {
+    if emit {
+        signals::block_termination();
+        let mut source = evemu::EvemuSource::open(path)?;
+        return remap(&mut source, load_config()?, Vec::new(), None);
+    }
+    let recording = evemu::parse(&std::fs::read_to_string(path)?)?;
+    let input = evemu::key_timeline(&recording);
+    println!("input:\n{}", format_timeline(&input));
+    println!("output:\n{}", format_timeline(&simulate(load_config()?, &input)));
+    Ok(())
+}
 
-    udev_loop::Udev::start_listen(Arc::new(Mutex::new(uloop)), buffer_cntr.clone());
-    return key_grabber::grab_kb_events(buffer_cntr.clone());
+fn main() -> Result<(), Box<dyn Error>> {
+    log::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args.as_slice() {
+        [] => run(),
+        ["test-config"] => test_config(),
+        ["record", path] => record(path),
+        ["replay", path] => replay(path, false),
+        ["replay", path, "--emit"] => replay(path, true),
+        _ => Err(USAGE.into()),
+    }
 }
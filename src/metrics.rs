@@ -0,0 +1,133 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.5,
+];
+
+/// How an output event came from its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Path {
+    /// The input key itself, let through unmatched
+    Passthrough,
+    /// First key sent by the action of a binding
+    Remapped,
+}
+
+impl Path {
+    fn name(self) -> &'static str {
+        match self {
+            Path::Passthrough => "passthrough",
+            Path::Remapped => "remapped",
+        }
+    }
+}
+
+/// When the input behind an output event was read
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    pub read_at: Instant,
+    pub path: Path,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative, each bucket counts everything up to its bound
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bound, count) in BUCKETS.iter().zip(&mut self.buckets) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, path: Path) {
+        let path = path.name();
+        for (bound, count) in BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{path=\"{path}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        let count = self.count;
+        let _ = writeln!(out, "{name}_bucket{{path=\"{path}\",le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{path=\"{path}\"}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{path=\"{path}\"}} {count}");
+    }
+}
+
+/// Input to output latency of the events sent, by path
+#[derive(Default)]
+pub struct Metrics {
+    passthrough: Mutex<Histogram>,
+    remapped: Mutex<Histogram>,
+}
+
+impl Metrics {
+    /// Counts an event sent just now
+    pub fn record(&self, stamp: Stamp) {
+        self.observe(stamp.path, stamp.read_at.elapsed());
+    }
+
+    pub fn observe(&self, path: Path, latency: Duration) {
+        let histogram = match path {
+            Path::Passthrough => &self.passthrough,
+            Path::Remapped => &self.remapped,
+        };
+        histogram.lock().unwrap().observe(latency.as_secs_f64());
+    }
+
+    /// Prometheus text format
+    pub fn render(&self) -> String {
+        const NAME: &str = "kbd_latency_seconds";
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP {NAME} Time from reading an input event to sending the output it caused"
+        );
+        let _ = writeln!(out, "# TYPE {NAME} histogram");
+        for (histogram, path) in [
+            (&self.passthrough, Path::Passthrough),
+            (&self.remapped, Path::Remapped),
+        ] {
+            histogram.lock().unwrap().render(&mut out, NAME, path);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Metrics::default();
+        metrics.observe(Path::Passthrough, Duration::from_micros(300));
+        metrics.observe(Path::Passthrough, Duration::from_millis(4));
+        metrics.observe(Path::Remapped, Duration::from_secs(3));
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "# TYPE kbd_latency_seconds histogram");
+        assert_eq!(
+            lines[2],
+            r#"kbd_latency_seconds_bucket{path="passthrough",le="0.0005"} 1"#
+        );
+        assert!(lines.contains(&r#"kbd_latency_seconds_bucket{path="passthrough",le="0.005"} 2"#));
+        assert!(lines.contains(&r#"kbd_latency_seconds_bucket{path="passthrough",le="+Inf"} 2"#));
+        assert!(lines.contains(&r#"kbd_latency_seconds_count{path="passthrough"} 2"#));
+        assert!(lines.contains(&r#"kbd_latency_seconds_bucket{path="remapped",le="2.5"} 0"#));
+        assert!(lines.contains(&r#"kbd_latency_seconds_bucket{path="remapped",le="+Inf"} 1"#));
+        assert!(lines.contains(&r#"kbd_latency_seconds_sum{path="remapped"} 3"#));
+    }
+}
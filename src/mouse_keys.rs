@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::clock::{ClockGuard, SafeClock};
+use crate::config::{MouseKeyAction, MouseKeysConfig};
+use crate::key_buffer::{Action, Axis, Event, Key, SafeSender};
+
+#[derive(Default)]
+struct State {
+    layer: bool,
+    // Keys whose press was taken by the layer, so is their release
+    taken: HashSet<Key>,
+    // Taken keys still down after the layer was let go
+    orphaned: HashSet<Key>,
+    moving: Vec<(i32, i32)>,
+    moving_since: i64,
+    // Sub-pixel motion carried over to the next tick
+    remainder: (f64, f64),
+    // Held scroll keys and when they last clicked
+    scrolling: HashMap<Key, i64>,
+    ticker: Option<ClockGuard>,
+}
+
+/// Pointer driven by the keyboard while the layer key is held
+pub struct MouseKeys {
+    config: Arc<MouseKeysConfig>,
+    clock: SafeClock,
+    sender: SafeSender,
+    state: Arc<Mutex<State>>,
+}
+
+impl MouseKeys {
+    pub fn new(config: MouseKeysConfig, clock: SafeClock, sender: SafeSender) -> Self {
+        MouseKeys {
+            config: Arc::new(config),
+            clock,
+            sender,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Takes the event if the layer owns it, otherwise it goes on as usual
+    pub fn handle(&self, event: &Event) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if event.key == self.config.layer {
+            match event.action {
+                Action::Press => state.layer = true,
+                Action::Release => self._let_go(&mut state),
+            }
+            return true;
+        }
+        match event.action {
+            Action::Press if state.layer && self.config.action(event.key).is_some() => {
+                state.taken.insert(event.key);
+            }
+            Action::Release if state.taken.remove(&event.key) => {}
+            Action::Release if state.orphaned.remove(&event.key) => return true,
+            _ => return false,
+        }
+        self._key(&mut state, event.key, event.action);
+        true
+    }
+
+    /// Same as letting go of the layer key
+    pub fn release_all(&self) {
+        self._let_go(&mut self.state.lock().unwrap());
+    }
+
+    fn _let_go(&self, state: &mut State) {
+        state.layer = false;
+        // Nothing stays held once the layer is let go
+        for key in state.taken.drain().collect::<Vec<_>>() {
+            self._key(state, key, Action::Release);
+            state.orphaned.insert(key);
+        }
+    }
+
+    fn _key(&self, state: &mut State, key: Key, action: Action) {
+        let Some(mouse_action) = self.config.action(key) else {
+            return;
+        };
+        let now = self.clock.now_ms();
+        match (mouse_action, action) {
+            (MouseKeyAction::Move(dx, dy), Action::Press) => {
+                if state.moving.is_empty() {
+                    state.moving_since = now;
+                    state.remainder = (0.0, 0.0);
+                }
+                state.moving.push((dx, dy));
+            }
+            (MouseKeyAction::Move(dx, dy), Action::Release) => {
+                if let Some(idx) = state.moving.iter().position(|m| *m == (dx, dy)) {
+                    state.moving.remove(idx);
+                }
+            }
+            (MouseKeyAction::Click(button), action) => {
+                self._send(button.into(), action);
+            }
+            (MouseKeyAction::Scroll(axis, amount), Action::Press) => {
+                self._send(Key::Scroll(axis, amount), Action::Press);
+                state.scrolling.insert(key, now);
+            }
+            (MouseKeyAction::Scroll(..), Action::Release) => {
+                state.scrolling.remove(&key);
+            }
+        }
+        if state.moving.is_empty() && state.scrolling.is_empty() {
+            state.ticker = None;
+        } else if state.ticker.is_none() {
+            state.ticker = Some(self._start_ticker());
+        }
+    }
+
+    fn _start_ticker(&self) -> ClockGuard {
+        let config = self.config.clone();
+        let clock = self.clock.clone();
+        let sender = self.sender.clone();
+        let state = self.state.clone();
+        self.clock.schedule_repeating(
+            chrono::Duration::milliseconds(config.tick_ms as i64),
+            Box::new(move || {
+                let mut state = state.lock().unwrap();
+                let now = clock.now_ms();
+                for event in tick(&config, &mut state, now) {
+                    sender.lock().unwrap().send(event).unwrap();
+                }
+            }),
+        )
+    }
+
+    fn _send(&self, key: Key, action: Action) {
+        self.sender
+            .lock()
+            .unwrap()
+            .send(Event { key, action })
+            .unwrap();
+    }
+}
+
+/// Motion and wheel clicks due at `now`
+fn tick(config: &MouseKeysConfig, state: &mut State, now: i64) -> Vec<Event> {
+    let mut out = Vec::new();
+    let press = |key| Event {
+        key,
+        action: Action::Press,
+    };
+    if !state.moving.is_empty() {
+        let speed = config.speed(now - state.moving_since);
+        let (dx, dy) = state
+            .moving
+            .iter()
+            .fold((0, 0), |(x, y), (dx, dy)| (x + dx, y + dy));
+        let x = dx.signum() as f64 * speed + state.remainder.0;
+        let y = dy.signum() as f64 * speed + state.remainder.1;
+        state.remainder = (x.fract(), y.fract());
+        for (axis, amount) in [(Axis::X, x.trunc() as i32), (Axis::Y, y.trunc() as i32)] {
+            if amount != 0 {
+                out.push(press(Key::Move(axis, amount)));
+            }
+        }
+    }
+    for (key, last) in state.scrolling.iter_mut() {
+        if now - *last >= config.scroll_ms as i64 {
+            *last = now;
+            if let Some(MouseKeyAction::Scroll(axis, amount)) = config.action(*key) {
+                out.push(press(Key::Scroll(axis, amount)));
+            }
+        }
+    }
+    out
+}
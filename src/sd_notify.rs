@@ -0,0 +1,150 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::log_warn;
+
+/// Tells systemd how kbd is doing, does nothing unless started by a
+/// `Type=notify` unit
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    // Half of WatchdogSec, as systemd recommends
+    ping_every: Option<Duration>,
+    last_ping: Mutex<Option<Instant>>,
+}
+
+/// Address of `NOTIFY_SOCKET`, a leading @ is the abstract namespace
+fn socket_addr(path: &str) -> std::io::Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+/// Ping interval for `WATCHDOG_USEC`, unless the watchdog is meant for
+/// another process
+fn ping_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let socket = std::env::var("NOTIFY_SOCKET").ok();
+        let usec = std::env::var("WATCHDOG_USEC").ok();
+        let pid = std::env::var("WATCHDOG_PID").ok();
+        let mut notifier = Notifier::new(socket.as_deref());
+        if notifier.socket.is_some() {
+            notifier.ping_every =
+                ping_interval(usec.as_deref(), pid.as_deref(), std::process::id());
+        }
+        notifier
+    }
+
+    pub fn new(path: Option<&str>) -> Self {
+        let socket = path.and_then(|path| match (UnixDatagram::unbound(), socket_addr(path)) {
+            (Ok(socket), Ok(addr)) => Some((socket, addr)),
+            (Err(e), _) | (_, Err(e)) => {
+                log_warn!("Can't notify systemd on {path}: {e}");
+                None
+            }
+        });
+        Notifier {
+            socket,
+            ping_every: None,
+            last_ping: Mutex::new(None),
+        }
+    }
+
+    /// Sends `KEY=value` lines, failures are only logged
+    pub fn notify(&self, state: &str) {
+        let Some((socket, addr)) = &self.socket else {
+            return;
+        };
+        if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+            log_warn!("Failed to notify systemd: {e}");
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=Stopping");
+    }
+
+    /// Keeps the systemd watchdog from firing, call it often, pings are
+    /// only sent as often as needed
+    pub fn ping(&self) {
+        let Some(every) = self.ping_every else {
+            return;
+        };
+        let mut last_ping = self.last_ping.lock().unwrap();
+        if last_ping.is_some_and(|last| last.elapsed() < every) {
+            return;
+        }
+        *last_ping = Some(Instant::now());
+        self.notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_interval() {
+        assert_eq!(
+            ping_interval(Some("10000000"), None, 7),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            ping_interval(Some("10000000"), Some("7"), 7),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(ping_interval(Some("10000000"), Some("8"), 7), None);
+        assert_eq!(ping_interval(Some("0"), None, 7), None);
+        assert_eq!(ping_interval(None, None, 7), None);
+    }
+
+    #[test]
+    fn test_notifier() {
+        let dir = std::env::temp_dir().join(format!("kbd-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let recv = || {
+            let mut buf = [0; 256];
+            let n = systemd.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+
+        let mut notifier = Notifier::new(path.to_str());
+        notifier.ready("Remapping kbd");
+        assert_eq!(recv(), "READY=1\nSTATUS=Remapping kbd");
+        notifier.ping_every = Some(Duration::from_secs(60));
+        notifier.ping();
+        notifier.ping();
+        notifier.stopping();
+        // The second ping was too early
+        assert_eq!(recv(), "WATCHDOG=1");
+        assert_eq!(recv(), "STOPPING=1\nSTATUS=Stopping");
+
+        // Outside systemd nothing happens
+        Notifier::new(None).ready("Remapping kbd");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,35 @@
+use std::mem::MaybeUninit;
+use std::thread;
+
+fn termination_signals() -> libc::sigset_t {
+    let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+    unsafe {
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+/// Keeps SIGTERM and SIGINT from this thread and from every thread it
+/// spawns afterwards, so that only `on_termination` sees them
+pub fn block_termination() {
+    let set = termination_signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+}
+
+/// Runs `handler` with the signal each time SIGTERM or SIGINT arrives,
+/// needs `block_termination` first. The thread waits for as long as the
+/// process lives and is never joined.
+pub fn on_termination(mut handler: impl FnMut(i32) + Send + 'static) {
+    thread::spawn(move || {
+        let set = termination_signals();
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                handler(signal);
+            }
+        }
+    });
+}
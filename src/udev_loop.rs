@@ -1,16 +1,106 @@
+#![allow(dead_code)]
 use super::key_buffer::KeyBuffer;
-use crate::key_buffer::{Action, Event};
-use Result;
+use crate::key_buffer::{Action, Axis, Event, Key, Popped, UButton};
+use crate::watchdog::Watchdog;
+use crate::{log_error, log_trace};
+use uinput::event::relative::{Position, Wheel};
+use std::collections::HashSet;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 type Res = Result<(), Box<dyn Error>>;
-pub type ALoop = Arc<Mutex<Udev>>;
-use crate::debug_println;
+const PANIC_RELEASE_WAIT: Duration = Duration::from_millis(100);
+pub type ALoop = Arc<Mutex<dyn OutputSink>>;
 
+/// Where remapped events end up
+pub trait OutputSink: Send {
+    fn send_event(&mut self, event: Event) -> Res;
+    fn sync(&mut self) -> Res;
+    /// Lets go of every key and button still down
+    fn release_all(&mut self) -> Res;
+}
+
+/// Keys and buttons an output has pressed and not released yet
+#[derive(Default)]
+pub struct HeldKeys(HashSet<Key>);
+
+impl HeldKeys {
+    pub fn track(&mut self, event: &Event) {
+        if event.key.is_relative() {
+            return;
+        }
+        match event.action {
+            Action::Press => self.0.insert(event.key),
+            Action::Release => self.0.remove(&event.key),
+        };
+    }
+
+    /// Release events for everything held, each key is forgotten once its
+    /// release is tracked
+    pub fn releases(&self) -> Vec<Event> {
+        self.0
+            .iter()
+            .map(|&key| Event {
+                key,
+                action: Action::Release,
+            })
+            .collect()
+    }
+}
+
+/// Sends every release even when some fail, those keys stay held for the
+/// next try. The first error is returned.
+fn release_all(sink: &mut dyn OutputSink, releases: Vec<Event>) -> Res {
+    let mut result = Ok(());
+    for event in releases {
+        if let Err(e) = sink.send_event(event) {
+            result = result.and(Err(e));
+        }
+    }
+    result.and(sink.sync())
+}
+
+/// Lets go of every key on `output` when any thread panics, release builds
+/// abort right after the hook
+pub fn release_on_panic(output: ALoop) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // The panicking thread may be the one holding the output
+        let start = Instant::now();
+        loop {
+            match output.try_lock() {
+                Ok(mut output) => {
+                    let _ = output.release_all();
+                    break;
+                }
+                Err(TryLockError::Poisoned(output)) => {
+                    let _ = output.into_inner().release_all();
+                    break;
+                }
+                Err(TryLockError::WouldBlock) if start.elapsed() < PANIC_RELEASE_WAIT => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(TryLockError::WouldBlock) => break,
+            }
+        }
+        default_hook(info);
+    }));
+}
+
+/// Name of the virtual pointer, never grabbed as an input
+pub const POINTER_NAME: &str = "remapper pointer";
+
+/// Virtual keyboard, and a separate virtual mouse so the desktop doesn't
+/// take the keyboard for a pointer
 pub struct Udev {
     device: uinput::Device,
+    pointer: uinput::Device,
+    // Devices written to since the last sync
+    device_dirty: bool,
+    pointer_dirty: bool,
+    held: HeldKeys,
 }
 
 impl Udev {
@@ -19,32 +109,226 @@ impl Udev {
             .name("remapper")?
             .event(uinput::event::Keyboard::All)?
             .create()?;
+        let mut pointer = uinput::default()?.name(POINTER_NAME)?;
+        for button in [
+            UButton::Left,
+            UButton::Right,
+            UButton::Middle,
+            UButton::Side,
+            UButton::Extra,
+            UButton::Forward,
+            UButton::Back,
+            UButton::Task,
+        ] {
+            pointer = pointer.event(button)?;
+        }
+        let pointer = pointer
+            .event(Position::X)?
+            .event(Position::Y)?
+            .event(Wheel::Vertical)?
+            .event(Wheel::Horizontal)?
+            .create()?;
 
-        Ok(Udev { device: uinput_dev })
+        Ok(Udev {
+            device: uinput_dev,
+            pointer,
+            device_dirty: false,
+            pointer_dirty: false,
+            held: HeldKeys::default(),
+        })
     }
-    pub fn send_event(&mut self, event: Event) -> Res {
-        debug_println!("Send event {:?}", event);
-        match event.action {
-            Action::Press => self.device.press(&event.key)?,
-            Action::Release => self.device.release(&event.key)?,
+
+    /// Sends what the buffer pops until it is shut down, then lets go of
+    /// every key. The latency of stamped events is counted once synced.
+    pub fn start_listen(
+        udev: ALoop,
+        buffer: Arc<KeyBuffer>,
+        watchdog: Option<Arc<Watchdog>>,
+    ) -> JoinHandle<()> {
+        buffer.set_output(udev.clone());
+        thread::spawn(move || {
+            let _alive = watchdog.as_ref().map(|w| w.output_alive());
+            // Stamps of the events written since the last sync, framed
+            // events always have one
+            let mut unsynced = Vec::new();
+            while let Some(popped) = buffer.pop_output() {
+                let _busy = watchdog.as_ref().map(|w| w.output());
+                let mut this = udev.lock().unwrap();
+                // Events of an input frame go out together, like they came in
+                let sent = match popped {
+                    Popped::Event((event, stamp)) => {
+                        unsynced.extend(stamp);
+                        this.send_event(event).and_then(|_| this.sync())
+                    }
+                    Popped::Framed((event, stamp)) => {
+                        unsynced.extend(stamp);
+                        match this.send_event(event) {
+                            // Synced with the end of its frame
+                            Ok(()) => continue,
+                            sent => sent,
+                        }
+                    }
+                    Popped::FrameEnd if unsynced.is_empty() => continue,
+                    Popped::FrameEnd => this.sync(),
+                };
+                match sent {
+                    Ok(()) => {
+                        for stamp in unsynced.drain(..) {
+                            buffer.metrics().record(stamp);
+                        }
+                    }
+                    // A failed write may have left keys down, the next
+                    // events still get their chance
+                    Err(e) => {
+                        log_error!("Failed to send an event: {e}");
+                        unsynced.clear();
+                        let _ = this.release_all();
+                    }
+                }
+            }
+            if let Err(e) = udev.lock().unwrap().release_all() {
+                log_error!("Failed to release keys: {e}");
+            }
+        })
+    }
+}
+
+impl OutputSink for Udev {
+    fn send_event(&mut self, event: Event) -> Res {
+        log_trace!("Send"; key = event.key, pressed = (event.action == Action::Press));
+        let value = match event.action {
+            Action::Press => 1,
+            Action::Release => 0,
+        };
+        match event.key {
+            Key::Keyboard(key) => match event.action {
+                Action::Press => self.device.press(&key)?,
+                Action::Release => self.device.release(&key)?,
+            },
+            Key::Button(button) => self.pointer.send(button, value)?,
+            // Nothing to undo for motion and wheel
+            Key::Move(..) | Key::Scroll(..) if event.action == Action::Release => return Ok(()),
+            Key::Move(Axis::X, amount) => self.pointer.send(Position::X, amount)?,
+            Key::Move(Axis::Y, amount) => self.pointer.send(Position::Y, amount)?,
+            Key::Scroll(Axis::X, amount) => self.pointer.send(Wheel::Horizontal, amount)?,
+            Key::Scroll(Axis::Y, amount) => self.pointer.send(Wheel::Vertical, amount)?,
         }
+        match event.key {
+            Key::Keyboard(_) => self.device_dirty = true,
+            _ => self.pointer_dirty = true,
+        }
+        // Only once written, a press that failed is not held and a release
+        // that failed is tried again by release_all
+        self.held.track(&event);
         Ok(())
     }
 
-    pub fn sync(&mut self) -> Res {
-        self.device.synchronize()?;
+    fn sync(&mut self) -> Res {
+        if self.device_dirty {
+            self.device.synchronize()?;
+            self.device_dirty = false;
+        }
+        if self.pointer_dirty {
+            self.pointer.synchronize()?;
+            self.pointer_dirty = false;
+        }
         Ok(())
     }
-    pub fn start_listen(udev: ALoop, buffer: Arc<KeyBuffer>) {
-        thread::spawn(move || {
-            let mut this = udev.lock().unwrap();
-            loop {
-                if let Some(event) = buffer.pop() {
-                    debug_println!("Send {:?}", event);
-                    this.send_event(event).unwrap();
-                    this.sync().unwrap();
-                }
+
+    fn release_all(&mut self) -> Res {
+        let releases = self.held.releases();
+        release_all(self, releases)
+    }
+}
+
+/// Collects sent events instead of emitting them
+#[derive(Default)]
+pub struct MemorySink {
+    pub events: Vec<Event>,
+    pub syncs: usize,
+    held: HeldKeys,
+}
+
+impl OutputSink for MemorySink {
+    fn send_event(&mut self, event: Event) -> Res {
+        self.held.track(&event);
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Res {
+        self.syncs += 1;
+        Ok(())
+    }
+
+    fn release_all(&mut self) -> Res {
+        let releases = self.held.releases();
+        release_all(self, releases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_buffer::UKey;
+
+    /// Fails every write of one key
+    #[derive(Default)]
+    struct FailingSink {
+        broken: Option<Key>,
+        events: Vec<Event>,
+        held: HeldKeys,
+    }
+
+    impl OutputSink for FailingSink {
+        fn send_event(&mut self, event: Event) -> Res {
+            if self.broken == Some(event.key) {
+                return Err(format!("can't write {}", event.key).into());
             }
-        });
+            self.held.track(&event);
+            self.events.push(event);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Res {
+            Ok(())
+        }
+
+        fn release_all(&mut self) -> Res {
+            let releases = self.held.releases();
+            release_all(self, releases)
+        }
+    }
+
+    #[test]
+    fn test_release_all_keeps_going() {
+        let mut sink = FailingSink::default();
+        for key in [UKey::A, UKey::B, UKey::C] {
+            sink.send_event(Event {
+                key: key.into(),
+                action: Action::Press,
+            })
+            .unwrap();
+        }
+        sink.events.clear();
+        sink.broken = Some(UKey::B.into());
+        assert!(sink.release_all().is_err());
+        // The others went up, B is still held for the next try
+        let mut released: Vec<_> = sink.events.drain(..).map(|e| e.key).collect();
+        released.sort_by_key(|key| key.to_string());
+        assert_eq!(released, vec![UKey::A.into(), UKey::C.into()]);
+
+        sink.broken = None;
+        sink.release_all().unwrap();
+        assert_eq!(
+            sink.events,
+            vec![Event {
+                key: UKey::B.into(),
+                action: Action::Release
+            }]
+        );
+        sink.events.clear();
+        sink.release_all().unwrap();
+        assert!(sink.events.is_empty());
     }
 }
@@ -1,7 +0,0 @@
-#[macro_export]
-macro_rules! debug_println {
-    ($($arg:tt)*) => {
-        #[cfg(debug_assertions)]
-        println!($($arg)*);
-    };
-}
\ No newline at end of file
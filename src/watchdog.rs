@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Stamp of a stage that is not busy
+const IDLE: i64 = -1;
+
+/// Notices a pipeline that stopped moving. Handing an input event to the
+/// buffer and sending an event out are each stamped while they run, a
+/// stamp older than `bound_ms`, or an output thread that is gone, is a
+/// stall.
+pub struct Watchdog {
+    start: Instant,
+    bound_ms: i64,
+    input_since: AtomicI64,
+    output_since: AtomicI64,
+    output_gone: AtomicBool,
+    // Set once stalled, input is left to the system from then on
+    tripped: AtomicBool,
+}
+
+/// Marks its stage busy until dropped
+pub struct Busy<'a>(&'a AtomicI64);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.store(IDLE, Ordering::SeqCst);
+    }
+}
+
+/// Kept by the output thread, dropping it says the thread is gone
+pub struct Alive(Arc<Watchdog>);
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        self.0.output_gone.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Watchdog {
+    pub fn new(bound_ms: u64) -> Arc<Self> {
+        Arc::new(Watchdog {
+            start: Instant::now(),
+            bound_ms: bound_ms as i64,
+            input_since: AtomicI64::new(IDLE),
+            output_since: AtomicI64::new(IDLE),
+            output_gone: AtomicBool::new(false),
+            tripped: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether a stall was found, the devices are ungrabbed by then
+    pub fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+
+    fn busy<'a>(&self, stamp: &'a AtomicI64) -> Busy<'a> {
+        stamp.store(self.now_ms(), Ordering::SeqCst);
+        Busy(stamp)
+    }
+
+    /// Held while an input event is pushed into the buffer
+    pub fn input(&self) -> Busy<'_> {
+        self.busy(&self.input_since)
+    }
+
+    /// Held from popping an event until it is sent
+    pub fn output(&self) -> Busy<'_> {
+        self.busy(&self.output_since)
+    }
+
+    pub fn output_alive(self: &Arc<Self>) -> Alive {
+        Alive(self.clone())
+    }
+
+    /// What is stuck as of `now_ms`, if anything
+    fn stalled(&self, now_ms: i64) -> Option<&'static str> {
+        let stuck = |stamp: &AtomicI64| {
+            let since = stamp.load(Ordering::SeqCst);
+            since != IDLE && now_ms - since > self.bound_ms
+        };
+        if self.output_gone.load(Ordering::SeqCst) {
+            Some("the output thread is gone")
+        } else if stuck(&self.input_since) {
+            Some("input is stuck in the buffer")
+        } else if stuck(&self.output_since) {
+            Some("output is stuck")
+        } else {
+            None
+        }
+    }
+
+    /// Checks on the pipeline in a thread of its own until `stop` is set.
+    /// `on_stall` runs once with the reason on the first stall. `on_alive`
+    /// runs after every check until then, with the pings gone systemd's
+    /// watchdog restarts kbd.
+    pub fn watch(
+        self: Arc<Self>,
+        stop: Arc<AtomicBool>,
+        mut on_alive: impl FnMut() + Send + 'static,
+        on_stall: impl FnOnce(&str) + Send + 'static,
+    ) -> JoinHandle<()> {
+        let interval = Duration::from_millis((self.bound_ms as u64 / 4).max(1));
+        thread::spawn(move || {
+            let mut on_stall = Some(on_stall);
+            while !stop.load(Ordering::SeqCst) {
+                if let Some(reason) = self.stalled(self.now_ms())
+                    && let Some(on_stall) = on_stall.take()
+                {
+                    self.tripped.store(true, Ordering::SeqCst);
+                    on_stall(reason);
+                }
+                if !self.tripped() {
+                    on_alive();
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::config::config_from_str;
+    use crate::key_buffer::{Action, KeyBuffer, UKey};
+    use crate::udev_loop::{MemorySink, Udev};
+    use std::sync::{Mutex, mpsc};
+
+    #[test]
+    fn test_watchdog_stalled() {
+        let watchdog = Watchdog::new(100);
+        assert_eq!(watchdog.stalled(1000), None);
+        {
+            let _busy = watchdog.input();
+            let now = watchdog.now_ms();
+            assert_eq!(watchdog.stalled(now + 100), None);
+            assert_eq!(
+                watchdog.stalled(now + 101),
+                Some("input is stuck in the buffer")
+            );
+        }
+        assert_eq!(watchdog.stalled(1000), None);
+        let busy = watchdog.output();
+        assert_eq!(watchdog.stalled(1000), Some("output is stuck"));
+        drop(busy);
+
+        let alive = watchdog.output_alive();
+        assert_eq!(watchdog.stalled(1000), None);
+        drop(alive);
+        assert_eq!(watchdog.stalled(0), Some("the output thread is gone"));
+    }
+
+    #[test]
+    fn test_watchdog_watch() {
+        let watchdog = Watchdog::new(20);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let alive = Arc::new(AtomicI64::new(0));
+        let pings = alive.clone();
+        let handle = watchdog.clone().watch(
+            stop.clone(),
+            move || {
+                pings.fetch_add(1, Ordering::SeqCst);
+            },
+            move |reason| {
+                tx.send(reason.to_string()).unwrap();
+            },
+        );
+        let _busy = watchdog.output();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "output is stuck"
+        );
+        assert!(watchdog.tripped());
+        // Reports the stall only once and stops pinging
+        let pinged = alive.load(Ordering::SeqCst);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(alive.load(Ordering::SeqCst), pinged);
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        stop.store(false, Ordering::SeqCst);
+
+        // Stopped before anything went wrong
+        let handle = Watchdog::new(20).watch(stop.clone(), || {}, |_| panic!("stalled"));
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_watchdog_output_deadlock() {
+        let buffer =
+            KeyBuffer::with_clock(config_from_str("[main]\n"), VirtualClock::new()).unwrap();
+        let sink = Arc::new(Mutex::new(MemorySink::default()));
+        let watchdog = Watchdog::new(20);
+        Udev::start_listen(sink.clone(), buffer.clone(), Some(watchdog.clone()));
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        watchdog.watch(
+            stop,
+            || {},
+            move |reason| tx.send(reason.to_string()).unwrap(),
+        );
+
+        // Whoever holds the output never lets go
+        let held = sink.lock().unwrap();
+        buffer.push(UKey::A, Action::Press);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "output is stuck"
+        );
+        drop(held);
+    }
+}